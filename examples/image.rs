@@ -1,10 +1,8 @@
 use anyhow::Result;
 use clap::Parser;
+use ffc::ffc::batch::{collapse_batch, Dedup};
 use ffc::prelude::*;
 use image::io::Reader as ImageReader;
-use image::{DynamicImage, GenericImage, GenericImageView, Pixel, Rgba};
-use rand::seq::SliceRandom;
-use std::collections::HashMap;
 use std::path::PathBuf;
 
 /// Example application of FFC, allowing the generation of collapsed images of far greater size than before
@@ -26,9 +24,22 @@ struct Args {
     #[clap(long)]
     reroll_attempts: Option<usize>,
 
-    // TODO: Add support back for this
-    // #[clap(short, long)]
-    // count : Option<usize>,
+    /// How many images to generate, numbered after the output name when there is more than one
+    #[clap(short, long)]
+    count: Option<usize>,
+    /// Seeds every image, so the same seed always gives the same images
+    #[clap(long)]
+    seed: Option<u64>,
+    /// Give up after this many attempts, 4 per image by default
+    #[clap(long)]
+    max_attempts: Option<usize>,
+    /// How many images to work on at once, one per CPU core by default. The images are the same however many are used
+    #[clap(long)]
+    threads: Option<usize>,
+    /// Skip images identical to one already generated
+    #[clap(long)]
+    distinct: bool,
+
     #[clap(short, long)]
     output: Option<PathBuf>,
 
@@ -44,35 +55,11 @@ fn main() -> Result<()> {
     let unset = 0;
     let outer = if unset_and_outer_are_equal { unset } else { 1 };
 
-    // 0 - Reserved for unset
-    // 1 - Reserved for outer
-    let mut unique_count: usize = 2;
-
     let img = ImageReader::open(args.source)?.decode()?;
 
-    let mut pattern = Grid::new(vec![0; (img.width() * img.height()) as usize], img.width() as usize);
-
-    // Map between pixels and their integer value
-    let mut pixel_to_int = HashMap::<u32, usize>::new();
-    let mut int_to_pixel = vec![0u32; (img.width() * img.height()) as usize];
-
-    for (pixel_idx, pixel) in img.pixels().enumerate() {
-        let pixel_rgba: u32 = u32::from_ne_bytes(pixel.2.to_rgba().0);
-        // println!("{pixel_rgba}");
-        // The unique id for this color
-        let pixel_color_id: usize;
-
-        if pixel_to_int.contains_key(&pixel_rgba) {
-            pixel_color_id = pixel_to_int.get(&pixel_rgba).unwrap().to_owned(); // Inefficient double lookup
-        } else {
-            pixel_color_id = unique_count;
-            pixel_to_int.insert(pixel_rgba, pixel_color_id);
-            int_to_pixel[pixel_color_id] = pixel_rgba;
-            unique_count += 1;
-        }
-
-        pattern.set(&pattern.i_to_pos(pixel_idx), pixel_color_id);
-    }
+    // 0 - Reserved for unset
+    // 1 - Reserved for outer
+    let (pattern, palette) = Grid::from_image(&img, 2);
 
     // ---
     // Generate the output images
@@ -86,53 +73,54 @@ fn main() -> Result<()> {
     // let mut rng = rand::thread_rng();
     // evaluate_order.shuffle(&mut rng);
 
-    for gen_num in 1..=1
-    //(args.count.unwrap_or(1))
-    {
-        let grid = initialize::<usize>(args.width, args.height, unset);
-
-        let collapsed = collapse(
-            grid,
-            &evaluate_order,
-            &pattern,
-            args.radius as isize,
-            args.reroll_attempts.unwrap_or(2),
-            1,     // TODO:
-            unset, // TODO:
-            outer,
+    let count = args.count.unwrap_or(1);
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |threads| threads.get()));
+    let dedup = if args.distinct { Dedup::Identical } else { Dedup::Off };
+
+    let (outputs, stats) = collapse_batch(
+        &initialize::<usize>(args.width, args.height, unset),
+        &evaluate_order,
+        &pattern,
+        args.radius as isize,
+        args.reroll_attempts.unwrap_or(2),
+        1,     // TODO:
+        unset, // TODO:
+        outer,
+        count,
+        args.max_attempts.unwrap_or(count * 4),
+        args.seed.unwrap_or_else(rand::random),
+        threads,
+        dedup,
+    );
+    println!(
+        "Generated {} of {count} grids in {} attempts ({} failed, {} duplicates)",
+        outputs.len(),
+        stats.attempts,
+        stats.failed,
+        stats.duplicates
+    );
+
+    let output = args.output.unwrap_or_else(|| PathBuf::from("out.png"));
+    for (gen_num, generated) in outputs.iter().enumerate() {
+        let path = if count == 1 {
+            output.clone()
+        } else {
+            let stem = output.file_stem().and_then(|stem| stem.to_str()).unwrap_or("out");
+            output.with_file_name(format!("{stem}_{}.png", gen_num + 1))
+        };
+
+        println!(
+            "Saving grid {} (seed {}) to {}",
+            gen_num + 1,
+            generated.seed,
+            path.display()
         );
-
-        match collapsed {
-            Some(generated_grid) => {
-                // We successfully generated this grid
-                println!("Finished generating grid {gen_num}");
-                let mut out_image = DynamicImage::new_rgba8(args.width as u32, args.height as u32);
-
-                for i in 0..generated_grid.get_area() {
-                    let pos = generated_grid.i_to_pos(i);
-                    let pixel_rgba_u32 = int_to_pixel[generated_grid.get(&pos, 1)];
-                    // println!("{pixel_rgba_u32}",);
-                    let pixel_rgba_4u8 = pixel_rgba_u32.to_ne_bytes();
-                    let pixel_rgba = Rgba::from_slice(&pixel_rgba_4u8);
-                    unsafe {
-                        out_image.unsafe_put_pixel(pos.x as u32, pos.y as u32, pixel_rgba.to_rgba());
-                    }
-                }
-
-                // println!("Saving grid {gen_num}");
-                out_image
-                    .save_with_format(
-                        args.output
-                            .clone()
-                            .unwrap_or(args.output.clone().unwrap_or_else(|| PathBuf::from("out.png"))),
-                        image::ImageFormat::Png,
-                    )
-                    .expect("Failed to save out.png");
-            }
-            None => {
-                println!("Failed to generate grid {gen_num}");
-            }
-        }
+        generated
+            .grid
+            .to_image(&palette)
+            .save_with_format(&path, image::ImageFormat::Png)?;
     }
 
     Ok(())
@@ -3,7 +3,7 @@ use ffc::{
     ffc::collapse_rules::{collapse_rule, CollapseRule},
     prelude::*,
 };
-use image::{DynamicImage, GenericImage, Pixel, Rgb};
+use image::Rgba;
 use std::path::PathBuf;
 
 /// Example application of FFC, allowing the generation of collapsed images of far greater size than before
@@ -16,8 +16,6 @@ struct Args {
     width: Option<usize>,
     #[clap(short, long)]
     height: Option<usize>,
-    #[clap(short, long)]
-    radius: Option<usize>,
     // #[clap(long)]
     // wrap: bool,
     #[clap(short, long)]
@@ -61,12 +59,11 @@ fn main() {
 
     let width = args.width.unwrap_or(16);
     let height = args.height.unwrap_or(16);
-    let radius = args.radius.unwrap_or(1);
 
     let grid = initialize(width, height, unset);
     let history_grid = initialize(width, height, unset);
 
-    let tile_options = vec![Tile::Water, Tile::Sand, Tile::Grass, Tile::Forest, Tile::Mountain];
+    let tile_options = [Tile::Water, Tile::Sand, Tile::Grass, Tile::Forest, Tile::Mountain];
 
     let unset_tile_rule = CollapseRule::False;
     let outer_tile_rule = CollapseRule::False;
@@ -117,36 +114,26 @@ fn main() {
         &history_grid,
         &tile_options[..],
         tile_to_rule,
-        radius as isize,
+        &[],
         unset,
         outer,
         256,
         seeds,
+        &[],
     );
 
     if let Some(generated_grid) = result {
-        let mut out_image = DynamicImage::new_rgba8(width as u32, height as u32);
-
-        for i in 0..generated_grid.get_area() {
-            let pos = generated_grid.i_to_pos(i);
-            let tile = generated_grid.get(&pos, Tile::Outer);
-
-            let color = match tile {
-                Tile::Unset => Rgb([0, 0, 0]),
-                Tile::Outer => Rgb([128, 0, 128]),
-                Tile::Water => Rgb([0, 0, 255]),
-                Tile::Sand => Rgb([255, 255, 0]),
-                Tile::Grass => Rgb([0, 255, 0]),
-                Tile::Forest => Rgb([0, 128, 0]),
-                Tile::Mountain => Rgb([128, 128, 128]),
-                // Tile::DeepWater => Rgb([0, 0, 128]),
-                // Tile::ShallowWater => Rgb([0, 128, 255]),
-            };
-
-            unsafe {
-                out_image.unsafe_put_pixel(pos.x as u32, pos.y as u32, color.to_rgba());
-            }
-        }
+        let out_image = generated_grid.to_image_with(|tile| match tile {
+            Tile::Unset => Rgba([0, 0, 0, 255]),
+            Tile::Outer => Rgba([128, 0, 128, 255]),
+            Tile::Water => Rgba([0, 0, 255, 255]),
+            Tile::Sand => Rgba([255, 255, 0, 255]),
+            Tile::Grass => Rgba([0, 255, 0, 255]),
+            Tile::Forest => Rgba([0, 128, 0, 255]),
+            Tile::Mountain => Rgba([128, 128, 128, 255]),
+            // Tile::DeepWater => Rgba([0, 0, 128, 255]),
+            // Tile::ShallowWater => Rgba([0, 128, 255, 255]),
+        });
 
         out_image
             .save_with_format(
@@ -1,9 +1,10 @@
 use clap::Parser;
 use ffc::{
-    ffc::collapse_rules::{collapse_rule, CollapseRule},
+    ffc::collapse_rules::{collapse_rule_observed, CollapseRule},
+    ffc::recorder::Recorder,
     prelude::*,
 };
-use image::{DynamicImage, GenericImage, Pixel, Rgb};
+use image::Rgba;
 use std::path::PathBuf;
 
 /// Example application of FFC, allowing the generation of collapsed images of far greater size than before
@@ -16,8 +17,6 @@ struct Args {
     width: Option<usize>,
     #[clap(short, long)]
     height: Option<usize>,
-    #[clap(short, long)]
-    radius: Option<usize>,
     // #[clap(long)]
     // wrap: bool,
     #[clap(short, long)]
@@ -34,6 +33,10 @@ struct Args {
 
     #[clap(short, long)]
     unset_and_outer_are_equal: Option<bool>,
+
+    // Writes an animation of the solver's progress to this path, as an APNG if it ends in .png and a GIF otherwise
+    #[clap(long)]
+    record: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -61,12 +64,11 @@ fn main() {
 
     let width = args.width.unwrap_or(16);
     let height = args.height.unwrap_or(16);
-    let radius = args.radius.unwrap_or(1);
 
     let grid = initialize(width, height, unset);
     let history_grid = initialize(width, height, unset);
 
-    let tile_options = vec![Tile::Water, Tile::Sand, Tile::Grass, Tile::Forest, Tile::Mountain];
+    let tile_options = [Tile::Water, Tile::Sand, Tile::Grass, Tile::Forest, Tile::Mountain];
 
     let unset_tile_rule = CollapseRule::False;
     let outer_tile_rule = CollapseRule::False;
@@ -112,41 +114,46 @@ fn main() {
         Tile::Mountain => &mountain_tile_rule,
     };
 
-    let result = collapse_rule(
+    let mut recorder = Recorder::new((width * height / 100).max(1));
+
+    let result = collapse_rule_observed(
         grid,
         &history_grid,
         &tile_options[..],
         tile_to_rule,
-        radius as isize,
+        &[],
         unset,
         outer,
         256,
         seeds,
+        &[],
+        &mut |grid, event| recorder.observe(grid, event),
     );
 
+    let tile_to_color = |tile: &Tile| match tile {
+        Tile::Unset => Rgba([0, 0, 0, 255]),
+        Tile::Outer => Rgba([128, 0, 128, 255]),
+        Tile::Water => Rgba([0, 0, 255, 255]),
+        Tile::Sand => Rgba([255, 255, 0, 255]),
+        Tile::Grass => Rgba([0, 255, 0, 255]),
+        Tile::Forest => Rgba([0, 128, 0, 255]),
+        Tile::Mountain => Rgba([128, 128, 128, 255]),
+        // Tile::DeepWater => Rgba([0, 0, 128, 255]),
+        // Tile::ShallowWater => Rgba([0, 128, 255, 255]),
+    };
+
+    if let Some(record_path) = &args.record {
+        let backtrack_color = Rgba([255, 0, 0, 255]);
+        let written = if record_path.extension().is_some_and(|extension| extension == "png") {
+            recorder.write_apng(record_path, tile_to_color, backtrack_color, 4, 50)
+        } else {
+            recorder.write_gif(record_path, tile_to_color, backtrack_color, 4, 50)
+        };
+        written.expect("Failed to write recording");
+    }
+
     if let Some(generated_grid) = result {
-        let mut out_image = DynamicImage::new_rgba8(width as u32, height as u32);
-
-        for i in 0..generated_grid.get_area() {
-            let pos = generated_grid.i_to_pos(i);
-            let tile = generated_grid.get(&pos, Tile::Outer);
-
-            let color = match tile {
-                Tile::Unset => Rgb([0, 0, 0]),
-                Tile::Outer => Rgb([128, 0, 128]),
-                Tile::Water => Rgb([0, 0, 255]),
-                Tile::Sand => Rgb([255, 255, 0]),
-                Tile::Grass => Rgb([0, 255, 0]),
-                Tile::Forest => Rgb([0, 128, 0]),
-                Tile::Mountain => Rgb([128, 128, 128]),
-                // Tile::DeepWater => Rgb([0, 0, 128]),
-                // Tile::ShallowWater => Rgb([0, 128, 255]),
-            };
-
-            unsafe {
-                out_image.unsafe_put_pixel(pos.x as u32, pos.y as u32, color.to_rgba());
-            }
-        }
+        let out_image = generated_grid.to_image_with(tile_to_color);
 
         out_image
             .save_with_format(
@@ -0,0 +1,200 @@
+use super::collapse_rules::{rule_contains, rule_footprint, CollapseRule, Truth};
+use super::compiled_rules::{compile_rule, evaluate_compiled_rule};
+use super::grid::Grid;
+use super::pos::Pos;
+use std::hash::Hash;
+
+// Problems with a ruleset that can be found before solving. Each tile is judged against an otherwise unset grid, and
+// setting more cells can only ever turn unknown parts of a rule into passes or failures, so anything reported here is
+// certain, though not every problem will be found. That doesn't hold for rules that check for Unset cells, call Custom
+// predicates or look at other layers, so tiles with those rules are listed as unchecked instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleAnalysis<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    // Tiles whose rule fails wherever they are put, before any neighbour has even been chosen
+    pub unplaceable: Vec<T>,
+    // Tiles that no tile (themselves included) can be placed directly next to, in any direction
+    pub isolated: Vec<T>,
+    // Tiles whose rule nests deeper than max_depth, so the deepest parts of it fail without being checked
+    pub too_deep: Vec<T>,
+    // Pairs of tiles that cannot be placed directly next to one another in any direction
+    pub incompatible: Vec<(T, T)>,
+    // Tiles left out of the checks above as their rules could pass on a filled in grid despite failing on a blank one
+    pub unchecked: Vec<T>,
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> RuleAnalysis<T> {
+    pub fn is_empty(&self) -> bool {
+        self.unplaceable.is_empty()
+            && self.isolated.is_empty()
+            && self.too_deep.is_empty()
+            && self.incompatible.is_empty()
+    }
+}
+
+// The number of levels of the rule that need evaluating, the rule fails outright if this is more than max_depth
+pub fn rule_depth<T: PartialEq + Eq + Hash + Clone + Sync + Send>(rule: &CollapseRule<T>) -> usize {
+    1 + match rule {
+        CollapseRule::And(sub_rules) | CollapseRule::Or(sub_rules) => {
+            sub_rules.iter().map(rule_depth).max().unwrap_or(0)
+        }
+        CollapseRule::Not(sub_rule)
+        | CollapseRule::Near(sub_rule, _)
+        | CollapseRule::NextTo(sub_rule)
+        | CollapseRule::NextTo1(sub_rule)
+        | CollapseRule::Left(sub_rule)
+        | CollapseRule::Right(sub_rule)
+        | CollapseRule::Up(sub_rule)
+        | CollapseRule::Down(sub_rule)
+        | CollapseRule::UpLeft(sub_rule)
+        | CollapseRule::UpRight(sub_rule)
+        | CollapseRule::DownLeft(sub_rule)
+        | CollapseRule::DownRight(sub_rule)
+        | CollapseRule::Parenthesis(sub_rule)
+        | CollapseRule::InLayer(_, sub_rule) => rule_depth(sub_rule),
+        CollapseRule::Is(_)
+        | CollapseRule::Was(_)
+        | CollapseRule::True
+        | CollapseRule::False
+        | CollapseRule::InBounds
+        | CollapseRule::Unset
+        | CollapseRule::Outer
+        | CollapseRule::Custom(_, _) => 0,
+    }
+}
+
+// | history_grid
+// + The history the ruleset will be solved with. Rules reading it (through Was) are checked at every position of a grid
+// + of its size, otherwise a small grid with room for every edge and corner is enough.
+pub fn analyze_rules<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
+    history_grid: &Grid<T>,
+    unset: T,
+    outer: T,
+    max_depth: usize,
+) -> RuleAnalysis<T> {
+    let compiled_rules = tile_options
+        .iter()
+        .map(|tile_option| compile_rule(tile_to_rule(tile_option), max_depth))
+        .collect::<Vec<_>>();
+
+    let checked = tile_options
+        .iter()
+        .map(|tile_option| {
+            !rule_contains(tile_to_rule(tile_option), &|rule| {
+                matches!(
+                    rule,
+                    CollapseRule::Unset | CollapseRule::Custom(_, _) | CollapseRule::InLayer(_, _)
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    let reads_history = (0..tile_options.len()).any(|option_i| {
+        checked[option_i]
+            && rule_contains(tile_to_rule(&tile_options[option_i]), &|rule| {
+                matches!(rule, CollapseRule::Was(_))
+            })
+    });
+
+    // Big enough that a tile in the middle, and a neighbour to any side of it, can read everything they look at
+    // without running off of the grid
+    let reach = tile_options
+        .iter()
+        .flat_map(|tile_option| rule_footprint(tile_to_rule(tile_option), max_depth))
+        .map(|(dx, dy)| dx.abs().max(dy.abs()))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let size = (reach * 2 + 3) as usize;
+    let scratch_history;
+    let history_grid = match reads_history {
+        true => history_grid,
+        false => {
+            scratch_history = Grid::new(vec![unset.clone(); size * size], size);
+            &scratch_history
+        }
+    };
+    let mut grid = Grid::new(vec![unset.clone(); history_grid.get_area()], history_grid.get_width());
+
+    let passes = |grid: &Grid<T>, pos: &Pos, option_i: usize| {
+        evaluate_compiled_rule(grid, history_grid, pos, &compiled_rules[option_i], &unset, &outer) != Truth::False
+    };
+
+    // Unplaceable if the rule fails at every position, from the middle of the grid out to each of its edges and
+    // corners
+    let mut placeable = vec![true; tile_options.len()];
+    for option_i in (0..tile_options.len()).filter(|&option_i| checked[option_i]) {
+        placeable[option_i] = (0..grid.get_area()).any(|i| {
+            let pos = grid.i_to_pos(i);
+            grid.set(&pos, tile_options[option_i].clone());
+            let passed = passes(&grid, &pos, option_i);
+            grid.set(&pos, unset.clone());
+            passed
+        });
+    }
+
+    // Whether `a` can go directly next to `b`, checking b's rule as well as a's only when `check_b` is set
+    let mut next_to = |a: usize, b: usize, check_b: bool| {
+        (0..grid.get_area()).any(|i| {
+            let pos = grid.i_to_pos(i);
+            [(1, 0), (-1, 0), (0, 1), (0, -1)].iter().any(|&(dx, dy)| {
+                let neighbour_pos = pos.rel(dx, dy);
+                if !grid.is_valid(&neighbour_pos) {
+                    return false;
+                }
+                grid.set(&pos, tile_options[a].clone());
+                grid.set(&neighbour_pos, tile_options[b].clone());
+                let passed = passes(&grid, &pos, a) && (!check_b || passes(&grid, &neighbour_pos, b));
+                grid.set(&pos, unset.clone());
+                grid.set(&neighbour_pos, unset.clone());
+                passed
+            })
+        })
+    };
+
+    let placeable_options = (0..tile_options.len())
+        .filter(|&i| checked[i] && placeable[i])
+        .collect::<Vec<_>>();
+
+    let mut incompatible = vec![];
+    let mut has_neighbour = vec![false; tile_options.len()];
+    for (pair_i, &a) in placeable_options.iter().enumerate() {
+        for &b in placeable_options[pair_i..].iter() {
+            if next_to(a, b, true) {
+                has_neighbour[a] = true;
+                has_neighbour[b] = true;
+            } else {
+                incompatible.push((tile_options[a].clone(), tile_options[b].clone()));
+            }
+        }
+    }
+    // An unchecked tile's rule can't be judged here, so it counts as a neighbour whenever the checked tile's own rule
+    // allows it
+    for &a in placeable_options.iter() {
+        if !has_neighbour[a] {
+            has_neighbour[a] = (0..tile_options.len()).any(|b| !checked[b] && next_to(a, b, false));
+        }
+    }
+
+    RuleAnalysis {
+        unplaceable: (0..tile_options.len())
+            .filter(|&i| !placeable[i])
+            .map(|i| tile_options[i].clone())
+            .collect(),
+        isolated: placeable_options
+            .iter()
+            .filter(|&&i| !has_neighbour[i])
+            .map(|&i| tile_options[i].clone())
+            .collect(),
+        too_deep: tile_options
+            .iter()
+            .filter(|tile_option| rule_depth(tile_to_rule(tile_option)) > max_depth)
+            .cloned()
+            .collect(),
+        incompatible,
+        unchecked: (0..tile_options.len())
+            .filter(|&i| !checked[i])
+            .map(|i| tile_options[i].clone())
+            .collect(),
+    }
+}
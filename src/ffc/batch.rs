@@ -0,0 +1,180 @@
+use super::collapse::collapse_with_rng;
+use super::collapse_rules::{collapse_rule_with_rng, CollapseRule, BACKTRACK_ATTEMPTS};
+use super::constraints::GlobalConstraint;
+use super::grid::Grid;
+use super::restart::run_seed;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::hash::Hash;
+
+// Generating many outputs from the one prepared sample or ruleset, such as to pre-bake level variants. Every attempt
+// has its own seed worked out from a base seed, and attempts are always accepted in the order of their seeds whatever
+// order the threads finish them in, so the same base seed gives the same batch however many threads are used.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dedup {
+    // Keep every output
+    Off,
+    // Reject outputs identical to one already kept
+    Identical,
+    // Reject outputs where at least this fraction (0.0 to 1.0) of cells match one already kept
+    Similarity(f32),
+}
+
+impl Dedup {
+    fn rejects<T: PartialEq + Eq + Hash + Clone + Sync + Send>(&self, grid: &Grid<T>, kept: &Grid<T>) -> bool {
+        match self {
+            Dedup::Off => false,
+            Dedup::Identical => grid.get_width() == kept.get_width() && grid.get_cells() == kept.get_cells(),
+            Dedup::Similarity(max_similarity) => similarity(grid, kept) >= *max_similarity,
+        }
+    }
+}
+
+// The fraction of cells that hold the same tile in both grids, or 0 if they aren't the same size
+pub fn similarity<T: PartialEq + Eq + Hash + Clone + Sync + Send>(a: &Grid<T>, b: &Grid<T>) -> f32 {
+    if a.get_width() != b.get_width() || a.get_area() != b.get_area() || a.get_area() == 0 {
+        return 0.0;
+    }
+    let matching = a.get_cells().iter().zip(b.get_cells()).filter(|(a, b)| a == b).count();
+    matching as f32 / a.get_area() as f32
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchOutput<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    // Which attempt this came from, counting from 0
+    pub attempt: usize,
+    // Passing this seed back into the generator gives this output again
+    pub seed: u64,
+    pub grid: Grid<T>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchStats {
+    pub attempts: usize,
+    pub failed: usize,
+    pub duplicates: usize,
+}
+
+// Calls `generate` with a freshly seeded rng until `count` outputs have been kept, or `max_attempts` attempts have been
+// made. Attempts are spread over `threads` threads (at least 1).
+pub fn generate_batch<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    count: usize,
+    max_attempts: usize,
+    base_seed: u64,
+    threads: usize,
+    dedup: Dedup,
+    generate: impl Fn(&mut StdRng) -> Option<Grid<T>> + Sync,
+) -> (Vec<BatchOutput<T>>, BatchStats) {
+    let mut kept: Vec<BatchOutput<T>> = vec![];
+    let mut stats = BatchStats::default();
+    let generate = &generate;
+
+    // Each round makes one attempt per thread, then accepts them in order. Any made past the attempt that completes
+    // the batch are thrown away without being counted, as a single thread would never have made them.
+    while kept.len() < count && stats.attempts < max_attempts {
+        let round_start = stats.attempts;
+        let round_end = (round_start + threads.max(1)).min(max_attempts);
+
+        let finished = std::thread::scope(|scope| {
+            let handles = (round_start..round_end)
+                .map(|attempt| {
+                    scope.spawn(move || {
+                        let seed = run_seed(base_seed, attempt);
+                        (attempt, seed, generate(&mut StdRng::seed_from_u64(seed)))
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Batch attempt panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        for (attempt, seed, grid) in finished {
+            if kept.len() >= count {
+                break;
+            }
+            stats.attempts = attempt + 1;
+            match grid {
+                None => stats.failed += 1,
+                Some(grid) if kept.iter().any(|output| dedup.rejects(&grid, &output.grid)) => stats.duplicates += 1,
+                Some(grid) => kept.push(BatchOutput { attempt, seed, grid }),
+            }
+        }
+    }
+
+    (kept, stats)
+}
+
+// A batch of collapse outputs from the one pattern
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_batch<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    evaluate_order: &[usize],
+    pattern: &Grid<T>,
+    radius: isize,
+    reroll_attempts: usize,
+    climb_amount_on_reroll: usize,
+    unset: T,
+    outer: T,
+    count: usize,
+    max_attempts: usize,
+    base_seed: u64,
+    threads: usize,
+    dedup: Dedup,
+) -> (Vec<BatchOutput<T>>, BatchStats) {
+    generate_batch(count, max_attempts, base_seed, threads, dedup, |rng| {
+        collapse_with_rng(
+            grid.clone(),
+            evaluate_order,
+            pattern,
+            radius,
+            reroll_attempts,
+            None,
+            climb_amount_on_reroll,
+            unset.clone(),
+            outer.clone(),
+            rng,
+        )
+    })
+}
+
+// A batch of collapse_rule outputs from the one ruleset
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_rule_batch<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    grid: &Grid<T>,
+    history_grid: &Grid<T>,
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T> + Sync,
+    tile_weights: &[f32],
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    constraints: &[GlobalConstraint<T>],
+    count: usize,
+    max_attempts: usize,
+    base_seed: u64,
+    threads: usize,
+    dedup: Dedup,
+) -> (Vec<BatchOutput<T>>, BatchStats) {
+    generate_batch(count, max_attempts, base_seed, threads, dedup, |rng| {
+        collapse_rule_with_rng(
+            grid.clone(),
+            history_grid,
+            tile_options,
+            &tile_to_rule,
+            tile_weights,
+            unset.clone(),
+            outer.clone(),
+            max_depth,
+            seeds,
+            constraints,
+            BACKTRACK_ATTEMPTS,
+            None,
+            rng,
+            &mut |_, _| {},
+        )
+    })
+}
@@ -1,5 +1,6 @@
 use super::grid::Grid;
 use rand::prelude::SliceRandom;
+use rand::Rng;
 use std::hash::Hash;
 
 pub fn initialize<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
@@ -10,15 +11,44 @@ pub fn initialize<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
     Grid::new(vec![unset; out_width * out_height], out_width)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn collapse<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: Grid<T>,
+    evaluate_order: &[usize],
+    pattern: &Grid<T>,
+    radius: isize,
+    reroll_attempts: usize,
+    climb_amount_on_reroll: usize,
+    unset: T,
+    outer: T,
+) -> Option<Grid<T>> {
+    collapse_with_rng(
+        grid,
+        evaluate_order,
+        pattern,
+        radius,
+        reroll_attempts,
+        None,
+        climb_amount_on_reroll,
+        unset,
+        outer,
+        &mut rand::thread_rng(),
+    )
+}
+
+// Same as collapse, but draws every random choice from `rng` so that seeding it gives the same output each time
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_with_rng<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
     mut grid: Grid<T>,
-    evaluate_order: &Vec<usize>,
+    evaluate_order: &[usize],
     pattern: &Grid<T>,
     radius: isize,
     reroll_attempts: usize,
+    max_dead_ends: Option<usize>,
     climb_amount_on_reroll: usize,
     unset: T,
     outer: T,
+    rng: &mut impl Rng,
 ) -> Option<Grid<T>> {
     // | evaluate_order
     // + evaluate_order is a list of indices where each index in the output grid exists once and only once.
@@ -33,18 +63,21 @@ pub fn collapse<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
     // + The maximum number of times a particular cell can be rerolled before we fall back to rerolling its parent.
     // + It is advised to keep this quite low
 
+    // | max_dead_ends
+    // + The most times in total that a cell can be left with no pattern to place before giving up, None for no limit
+
     // | climb_amount_on_reroll
     // + When we reroll we jump backward through the evaluate_order list by this number, generally advised to keep
     // + this at 1.
 
     let pattern_points = (0..pattern.get_area())
-        .into_iter()
         .map(|pattern_i| pattern.i_to_pos(pattern_i))
         .collect::<Vec<_>>();
 
     // roll_count serves as a stack to count the number of times we have rolled the current evaluation index (always the
     // top of the stack/last item)
     let mut roll_counts = vec![0];
+    let mut dead_ends = 0;
 
     macro_rules! fallback {
         () => {
@@ -60,8 +93,6 @@ pub fn collapse<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
         };
     }
 
-    let mut rng = rand::rngs::ThreadRng::default();
-
     while roll_counts.len() < evaluate_order.len() {
         if roll_counts.is_empty() {
             return None; // We've failed to generate anything
@@ -83,15 +114,19 @@ pub fn collapse<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
         // this tile's location
         let valid_pattern_pos_list = pattern_points
             .iter()
-            .filter(|p_pos| Grid::compare(pattern, &p_pos, &grid, &eval_pos, radius, unset.clone(), outer.clone()))
+            .filter(|p_pos| Grid::compare(pattern, p_pos, &grid, &eval_pos, radius, unset.clone(), outer.clone()))
             .collect::<Vec<_>>();
 
         if valid_pattern_pos_list.is_empty() {
             // We have nothing to put here, fall back to a previous step and roll again
+            dead_ends += 1;
+            if max_dead_ends.is_some_and(|max_dead_ends| dead_ends > max_dead_ends) {
+                return None;
+            }
             // fallback!();
         } else {
             // We have at-least one pattern we can super impose here, choose one at random
-            let selection_pos = valid_pattern_pos_list.choose(&mut rng).unwrap().to_owned();
+            let selection_pos = valid_pattern_pos_list.choose(rng).unwrap().to_owned();
             // println!("Pos: {} {}", selection_pos.x, selection_pos.y);
             grid.set(&eval_pos, pattern.get(selection_pos, outer.clone()));
 
@@ -1,9 +1,19 @@
+use super::compiled_rules::{check_compiled_rule, compile_rule};
+use super::constraints::{
+    check_constraints, check_constraints_after, constraints_allow, tile_counts, GlobalConstraint,
+};
 use super::grid::Grid;
 use super::pos::Pos;
 use priority_queue::PriorityQueue;
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::Rng;
 use std::cmp::Reverse;
+use std::collections::HashSet;
 use std::hash::Hash;
+use std::sync::Arc;
+
+// (grid, history_grid, pos) -> whether the rule passes
+pub type RulePredicate<T> = Arc<dyn Fn(&Grid<T>, &Grid<T>, &Pos) -> bool + Send + Sync>;
 
 pub enum CollapseRule<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
     And(Vec<CollapseRule<T>>),
@@ -29,10 +39,82 @@ pub enum CollapseRule<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
 
     True,
     False,
-    InBounds, // Can be used to check if this tile falls outside of the world bounds
+    InBounds, // Can be used to check if this tile falls inside of the world bounds
     Unset,    // Can be used to check if this tile has not been set yet
+    Outer,    // Can be used to check if this tile falls outside of the world bounds
+
+    // Calls out to a user supplied predicate, given the grid, history grid and position being checked. This is for
+    // anything the other rules cannot express, such as looking up a heightmap or noise value at this position. The
+    // number is how far (in cells, in any direction) the predicate reads from the grid, so that the solver knows to
+    // check it again when those cells change. Use 0 if it only reads its own cell, the history grid or nothing at all.
+    Custom(RulePredicate<T>, isize),
+
+    // Checks the rule against the same position in another layer of a LayeredGrid, such as to only allow trees on
+    // grass in the terrain layer. Only collapse_layers and collapse_layers_jointly know about layers, anywhere else
+    // there is no other layer to read, so this is Unknown.
+    InLayer(usize, Box<CollapseRule<T>>),
+}
+
+// The result of checking a rule against a grid that may still have unset cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Truth {
+    True,
+    False,
+    Unknown, // Depends on cells that have not been set yet
+}
+
+impl Truth {
+    // False if any are false, otherwise unknown if any are unknown
+    pub fn all(results: impl IntoIterator<Item = Truth>) -> Truth {
+        let mut all = Truth::True;
+        for result in results {
+            match result {
+                Truth::False => return Truth::False,
+                Truth::Unknown => all = Truth::Unknown,
+                Truth::True => {}
+            }
+        }
+        all
+    }
+
+    // True if any are true, otherwise unknown if any are unknown
+    pub fn any(results: impl IntoIterator<Item = Truth>) -> Truth {
+        let mut any = Truth::False;
+        for result in results {
+            match result {
+                Truth::True => return Truth::True,
+                Truth::Unknown => any = Truth::Unknown,
+                Truth::False => {}
+            }
+        }
+        any
+    }
+}
+
+impl From<bool> for Truth {
+    fn from(value: bool) -> Self {
+        if value {
+            Truth::True
+        } else {
+            Truth::False
+        }
+    }
 }
 
+impl std::ops::Not for Truth {
+    type Output = Truth;
+
+    fn not(self) -> Truth {
+        match self {
+            Truth::True => Truth::False,
+            Truth::False => Truth::True,
+            Truth::Unknown => Truth::Unknown,
+        }
+    }
+}
+
+// Whether the rule could still pass once the rest of the grid has been set, which is what the solver needs when
+// choosing options. Use evaluate_rule and compare against Truth::True to check that a finished grid strictly passes.
 pub fn check_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
     grid: &Grid<T>,
     history_grid: &Grid<T>,
@@ -42,13 +124,27 @@ pub fn check_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
     outer: T,
     max_depth: usize,
 ) -> bool {
+    evaluate_rule(grid, history_grid, pos, rule, unset, outer, max_depth) != Truth::False
+}
+
+// Any unset cell read by Is (or unset history cell read by Was) makes for an unknown result, which is carried up
+// through And, Or, Not, etc. using three-valued logic
+pub fn evaluate_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    history_grid: &Grid<T>,
+    pos: &Pos,
+    rule: &CollapseRule<T>,
+    unset: T,
+    outer: T,
+    max_depth: usize,
+) -> Truth {
     if max_depth == 0 {
-        return false;
+        return Truth::False;
     }
 
     macro_rules! sub_check_rule {
         ($sub_pos:expr, $sub_rule:expr) => {
-            check_rule(
+            evaluate_rule(
                 grid,
                 history_grid,
                 $sub_pos,
@@ -60,17 +156,32 @@ pub fn check_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
         };
     }
 
+    let next_to = [
+        Pos { x: pos.x, y: pos.y + 1 },
+        Pos { x: pos.x, y: pos.y - 1 },
+        Pos { x: pos.x + 1, y: pos.y },
+        Pos { x: pos.x - 1, y: pos.y },
+    ];
+
     match rule {
-        CollapseRule::And(sub_rules) => sub_rules.iter().all(move |sub_rule| sub_check_rule!(pos, sub_rule)),
-        CollapseRule::Or(sub_rules) => sub_rules.iter().any(move |sub_rule| sub_check_rule!(pos, sub_rule)),
+        CollapseRule::And(sub_rules) => Truth::all(sub_rules.iter().map(|sub_rule| sub_check_rule!(pos, sub_rule))),
+        CollapseRule::Or(sub_rules) => Truth::any(sub_rules.iter().map(|sub_rule| sub_check_rule!(pos, sub_rule))),
         CollapseRule::Not(sub_rule) => !sub_check_rule!(pos, sub_rule),
         CollapseRule::Is(tile_type) => {
             let tile = grid.get(pos, outer.clone());
-            tile == unset || tile == *tile_type
+            if tile == unset {
+                Truth::Unknown
+            } else {
+                Truth::from(tile == *tile_type)
+            }
         }
         CollapseRule::Was(tile_type) => {
             let tile: T = history_grid.get(pos, outer.clone());
-            tile == unset || tile == *tile_type
+            if tile == unset {
+                Truth::Unknown
+            } else {
+                Truth::from(tile == *tile_type)
+            }
         }
         CollapseRule::Left(sub_rule) => sub_check_rule!(&Pos { x: pos.x - 1, y: pos.y }, sub_rule),
         CollapseRule::Right(sub_rule) => sub_check_rule!(&Pos { x: pos.x + 1, y: pos.y }, sub_rule),
@@ -104,91 +215,567 @@ pub fn check_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
             },
             sub_rule
         ),
-        CollapseRule::Near(sub_rule, radius) => {
-            for dx in (-radius)..=*radius {
-                for dy in (-radius)..=*radius {
-                    if dx == 0 && dy == 0 {
-                        continue;
+        CollapseRule::Near(sub_rule, radius) => Truth::all(
+            ((-radius)..=*radius)
+                .flat_map(|dx| ((-radius)..=*radius).map(move |dy| (dx, dy)))
+                .filter(|&(dx, dy)| dx != 0 || dy != 0)
+                .map(|(dx, dy)| sub_check_rule!(&pos.rel(dx, dy), sub_rule)),
+        ),
+        CollapseRule::NextTo(sub_rule) => Truth::all(
+            next_to
+                .iter()
+                .map(|neighbour_pos| sub_check_rule!(neighbour_pos, sub_rule)),
+        ),
+        CollapseRule::NextTo1(sub_rule) => Truth::any(
+            next_to
+                .iter()
+                .map(|neighbour_pos| sub_check_rule!(neighbour_pos, sub_rule)),
+        ),
+        CollapseRule::Parenthesis(sub_rule) => {
+            sub_check_rule!(pos, sub_rule)
+        }
+        CollapseRule::True => Truth::True,
+        CollapseRule::False => Truth::False,
+        CollapseRule::InBounds => Truth::from(grid.is_valid(pos)),
+        CollapseRule::Unset => Truth::from(grid.get(pos, outer.clone()) == unset),
+        CollapseRule::Outer => Truth::from(!grid.is_valid(pos)),
+        CollapseRule::Custom(predicate, _) => Truth::from(predicate(grid, history_grid, pos)),
+        CollapseRule::InLayer(_, _) => Truth::Unknown,
+    }
+}
+
+// The offsets (relative to the cell being checked) of every grid cell that the rule could read within max_depth, in
+// other words the cells that can change the rule's result when they are set. Reads of the history grid are left out
+// as it does not change while solving, and Custom predicates are taken to read every cell within the radius they were
+// given.
+pub fn rule_footprint<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    rule: &CollapseRule<T>,
+    max_depth: usize,
+) -> Vec<(isize, isize)> {
+    fn visit<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+        rule: &CollapseRule<T>,
+        offset: (isize, isize),
+        max_depth: usize,
+        footprint: &mut HashSet<(isize, isize)>,
+    ) {
+        if max_depth == 0 {
+            return;
+        }
+
+        let mut visit_at = |dx: isize, dy: isize, sub_rule: &CollapseRule<T>| {
+            visit(sub_rule, (offset.0 + dx, offset.1 + dy), max_depth - 1, footprint)
+        };
+
+        match rule {
+            CollapseRule::And(sub_rules) | CollapseRule::Or(sub_rules) => {
+                sub_rules.iter().for_each(|sub_rule| visit_at(0, 0, sub_rule))
+            }
+            CollapseRule::Not(sub_rule) | CollapseRule::Parenthesis(sub_rule) => visit_at(0, 0, sub_rule),
+            CollapseRule::Left(sub_rule) => visit_at(-1, 0, sub_rule),
+            CollapseRule::Right(sub_rule) => visit_at(1, 0, sub_rule),
+            CollapseRule::Up(sub_rule) => visit_at(0, 1, sub_rule),
+            CollapseRule::Down(sub_rule) => visit_at(0, -1, sub_rule),
+            CollapseRule::UpLeft(sub_rule) => visit_at(-1, 1, sub_rule),
+            CollapseRule::UpRight(sub_rule) => visit_at(1, 1, sub_rule),
+            CollapseRule::DownLeft(sub_rule) => visit_at(-1, -1, sub_rule),
+            CollapseRule::DownRight(sub_rule) => visit_at(1, -1, sub_rule),
+            CollapseRule::Near(sub_rule, radius) => {
+                for dx in (-radius)..=*radius {
+                    for dy in (-radius)..=*radius {
+                        if dx != 0 || dy != 0 {
+                            visit_at(dx, dy, sub_rule);
+                        }
                     }
-                    let neighbour_pos = pos.rel(dx, dy);
-                    let neighour_result = sub_check_rule!(&neighbour_pos, sub_rule);
-                    if neighour_result == false {
-                        return false;
+                }
+            }
+            CollapseRule::NextTo(sub_rule) | CollapseRule::NextTo1(sub_rule) => {
+                visit_at(0, 1, sub_rule);
+                visit_at(0, -1, sub_rule);
+                visit_at(1, 0, sub_rule);
+                visit_at(-1, 0, sub_rule);
+            }
+            CollapseRule::Is(_) | CollapseRule::Unset => {
+                footprint.insert(offset);
+            }
+            CollapseRule::Custom(_, radius) => {
+                for dx in (-radius)..=*radius {
+                    for dy in (-radius)..=*radius {
+                        footprint.insert((offset.0 + dx, offset.1 + dy));
                     }
                 }
             }
-
-            true
-        }
-        CollapseRule::NextTo(sub_rule) => {
-            sub_check_rule!(&Pos { x: pos.x, y: pos.y + 1 }, sub_rule)
-                && sub_check_rule!(&Pos { x: pos.x, y: pos.y - 1 }, sub_rule)
-                && sub_check_rule!(&Pos { x: pos.x + 1, y: pos.y }, sub_rule)
-                && sub_check_rule!(&Pos { x: pos.x - 1, y: pos.y }, sub_rule)
-        }
-        CollapseRule::NextTo1(sub_rule) => {
-            sub_check_rule!(&Pos { x: pos.x, y: pos.y + 1 }, sub_rule)
-                || sub_check_rule!(&Pos { x: pos.x, y: pos.y - 1 }, sub_rule)
-                || sub_check_rule!(&Pos { x: pos.x + 1, y: pos.y }, sub_rule)
-                || sub_check_rule!(&Pos { x: pos.x - 1, y: pos.y }, sub_rule)
-        }
-        CollapseRule::Parenthesis(sub_rule) => {
-            sub_check_rule!(pos, sub_rule)
+            CollapseRule::Was(_)
+            | CollapseRule::True
+            | CollapseRule::False
+            | CollapseRule::InBounds
+            | CollapseRule::Outer
+            | CollapseRule::InLayer(_, _) => {}
         }
-        CollapseRule::True => true,
-        CollapseRule::False => false,
-        CollapseRule::InBounds => grid.is_valid(&pos),
-        CollapseRule::Unset => grid.get(&pos, outer.clone()) == outer,
     }
+
+    let mut footprint = HashSet::new();
+    visit(rule, (0, 0), max_depth, &mut footprint);
+
+    let mut footprint = footprint.into_iter().collect::<Vec<_>>();
+    footprint.sort();
+    footprint
 }
 
+// Whether any part of the rule (including the rule itself) is accepted by `is_match`
+pub fn rule_contains<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    rule: &CollapseRule<T>,
+    is_match: &dyn Fn(&CollapseRule<T>) -> bool,
+) -> bool {
+    is_match(rule)
+        || match rule {
+            CollapseRule::And(sub_rules) | CollapseRule::Or(sub_rules) => {
+                sub_rules.iter().any(|sub_rule| rule_contains(sub_rule, is_match))
+            }
+            CollapseRule::Not(sub_rule)
+            | CollapseRule::Near(sub_rule, _)
+            | CollapseRule::NextTo(sub_rule)
+            | CollapseRule::NextTo1(sub_rule)
+            | CollapseRule::Left(sub_rule)
+            | CollapseRule::Right(sub_rule)
+            | CollapseRule::Up(sub_rule)
+            | CollapseRule::Down(sub_rule)
+            | CollapseRule::UpLeft(sub_rule)
+            | CollapseRule::UpRight(sub_rule)
+            | CollapseRule::DownLeft(sub_rule)
+            | CollapseRule::DownRight(sub_rule)
+            | CollapseRule::Parenthesis(sub_rule)
+            | CollapseRule::InLayer(_, sub_rule) => rule_contains(sub_rule, is_match),
+            CollapseRule::Is(_)
+            | CollapseRule::Was(_)
+            | CollapseRule::True
+            | CollapseRule::False
+            | CollapseRule::InBounds
+            | CollapseRule::Unset
+            | CollapseRule::Outer
+            | CollapseRule::Custom(_, _) => false,
+        }
+}
+
+// What collapse_rule_observed reports back as it works
+#[derive(Debug, Clone)]
+pub enum SolverEvent<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    Placed { pos: Pos, tile: T },
+    // A placement being undone while backtracking
+    Removed { pos: Pos, tile: T },
+    Finished,
+    Failed,
+}
+
+// By default, how many more times the solver will try another tile in a cell, after backtracking back to it, before
+// giving up on it and backtracking further
+pub const BACKTRACK_ATTEMPTS: usize = 15;
+
+// | tile_weights
+// + One weight per tile option, giving how likely it is to be picked over the other valid options for a cell.
+// + When empty every valid option is equally likely. Any other number of weights is a mistake, so nothing is generated.
+#[allow(clippy::too_many_arguments)]
 pub fn collapse_rule<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    grid: Grid<T>,
+    history_grid: &Grid<T>,
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
+    tile_weights: &[f32],
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    constraints: &[GlobalConstraint<T>],
+) -> Option<Grid<T>> {
+    collapse_rule_observed(
+        grid,
+        history_grid,
+        tile_options,
+        tile_to_rule,
+        tile_weights,
+        unset,
+        outer,
+        max_depth,
+        seeds,
+        constraints,
+        &mut |_, _| {},
+    )
+}
+
+// Same as collapse_rule, but calls `observer` with the grid as it stands after every change, such as for recording
+// the solver's progress
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_rule_observed<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    grid: Grid<T>,
+    history_grid: &Grid<T>,
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
+    tile_weights: &[f32],
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    constraints: &[GlobalConstraint<T>],
+    observer: &mut impl FnMut(&Grid<T>, &SolverEvent<T>),
+) -> Option<Grid<T>> {
+    collapse_rule_with_rng(
+        grid,
+        history_grid,
+        tile_options,
+        tile_to_rule,
+        tile_weights,
+        unset,
+        outer,
+        max_depth,
+        seeds,
+        constraints,
+        BACKTRACK_ATTEMPTS,
+        None,
+        &mut rand::thread_rng(),
+        observer,
+    )
+}
+
+// Same as collapse_rule_observed, but draws every random choice from `rng` so that seeding it gives the same output
+// each time
+// | backtrack_attempts
+// + How many more times to try another tile in a cell after backtracking back to it, BACKTRACK_ATTEMPTS by default
+// | max_backtracks
+// + How many times in total the solver can run into a dead end and backtrack before giving up, None for no limit
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_rule_with_rng<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    grid: Grid<T>,
+    history_grid: &Grid<T>,
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
+    tile_weights: &[f32],
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    constraints: &[GlobalConstraint<T>],
+    backtrack_attempts: usize,
+    max_backtracks: Option<usize>,
+    rng: &mut impl Rng,
+    observer: &mut impl FnMut(&Grid<T>, &SolverEvent<T>),
+) -> Option<Grid<T>> {
+    solve_rules(
+        grid,
+        history_grid,
+        tile_options,
+        tile_to_rule,
+        tile_weights,
+        unset,
+        outer,
+        max_depth,
+        seeds,
+        constraints,
+        backtrack_attempts,
+        max_backtracks,
+        rng,
+        observer,
+        true,
+    )
+}
+
+// The solver behind every collapse_rule variant. Turning off `cache_options` checks every option's rule afresh every
+// time, which gives the same output as the cache only slower, so is only for checking the cache against.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn solve_rules<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
     mut grid: Grid<T>,
     history_grid: &Grid<T>,
     tile_options: &'a [T],
     tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
-    re_check_radius: isize,
+    tile_weights: &[f32],
     unset: T,
     outer: T,
     max_depth: usize,
     seeds: usize,
+    constraints: &[GlobalConstraint<T>],
+    backtrack_attempts: usize,
+    max_backtracks: Option<usize>,
+    rng: &mut impl Rng,
+    observer: &mut impl FnMut(&Grid<T>, &SolverEvent<T>),
+    cache_options: bool,
 ) -> Option<Grid<T>> {
+    if !tile_weights.is_empty() && tile_weights.len() != tile_options.len() {
+        observer(&grid, &SolverEvent::Failed);
+        return None;
+    }
+
     let mut placed_stack: Vec<(Pos, T, usize)> = vec![];
+    // Tiles that backjumping has found can't go in a cell, as (cell, tile, depth). Each only holds for as long as the
+    // first `depth` placements on the stack are left alone.
+    let mut ruled_out: Vec<(usize, T, usize)> = vec![];
     let mut front = PriorityQueue::new();
 
-    let mut rng = rand::rngs::ThreadRng::default();
+    // Each option's rule only needs to be walked and simplified the once
+    let compiled_rules = tile_options
+        .iter()
+        .map(|tile_option| compile_rule(tile_to_rule(tile_option), max_depth))
+        .collect::<Vec<_>>();
+
+    // Kept up to date as tiles are placed and removed so that global constraints can be checked without rescanning
+    // the grid
+    let mut counts = tile_counts(&grid);
 
     for _ in 0..seeds {
         let starting_point = rng.gen_range(0..grid.get_area());
         front.push(starting_point, Reverse(0));
     }
 
-    let steps_to_print = grid.get_area() / 105;
+    let steps_to_print = (grid.get_area() / 105).max(1);
     let mut clock_to_print = steps_to_print;
 
+    // Every offset that any option's rule reads from. Turned around, these are the cells whose options can change when
+    // a cell is set.
+    let mut footprint = tile_options
+        .iter()
+        .flat_map(|tile_option| rule_footprint(tile_to_rule(tile_option), max_depth))
+        .collect::<Vec<_>>();
+    footprint.sort();
+    footprint.dedup();
+    let dependents = footprint.iter().map(|(dx, dy)| (-dx, -dy)).collect::<Vec<_>>();
+
+    // Backjumping relies on a rule that fails staying failed as more cells are set. That stops holding once a rule
+    // checks for unset cells, negates anything read from the grid or calls a Custom predicate.
+    let monotone = tile_options.iter().all(|tile_option| {
+        !rule_contains(tile_to_rule(tile_option), &|rule| match rule {
+            CollapseRule::Unset | CollapseRule::Custom(_, _) => true,
+            CollapseRule::Not(sub_rule) => rule_contains(sub_rule, &|sub_rule| {
+                matches!(
+                    sub_rule,
+                    CollapseRule::Is(_)
+                        | CollapseRule::Unset
+                        | CollapseRule::Custom(_, _)
+                        | CollapseRule::InLayer(_, _)
+                )
+            }),
+            _ => false,
+        })
+    });
+
+    // The options (as indices into tile_options) whose rules pass at each cell. A cell's entry is cleared whenever a
+    // cell within its footprint changes.
+    let mut option_cache: Vec<Option<Vec<usize>>> = vec![None; grid.get_area()];
+
+    // Which options the cardinality constraints still leave room for. These only depend on the tile counts, so they
+    // are worked out again once per placement rather than for every option of every cell that is looked at.
+    let mut count_allowed: Vec<bool> = vec![];
+    let mut grid_changes = 0;
+    let mut count_allowed_at = None;
+
     macro_rules! find_valid_options {
-        ($find_pos: expr) => {
-            tile_options
+        ($find_pos: expr) => {{
+            if count_allowed_at != Some(grid_changes) {
+                count_allowed = tile_options
+                    .iter()
+                    .map(|tile_option| constraints_allow(&grid, &counts, constraints, tile_option, unset.clone()))
+                    .collect();
+                count_allowed_at = Some(grid_changes);
+            }
+
+            let find_i = grid.pos_to_i($find_pos);
+            let passes = |option_i: usize| {
+                check_compiled_rule(
+                    &grid,
+                    history_grid,
+                    $find_pos,
+                    &compiled_rules[option_i],
+                    &unset,
+                    &outer,
+                )
+            };
+            let checked_options;
+            let rule_options: &Vec<usize> = match cache_options {
+                true => option_cache[find_i]
+                    .get_or_insert_with(|| (0..tile_options.len()).filter(|&option_i| passes(option_i)).collect()),
+                false => {
+                    checked_options = (0..tile_options.len())
+                        .filter(|&option_i| passes(option_i))
+                        .collect::<Vec<_>>();
+                    &checked_options
+                }
+            };
+
+            // Constraints depend on the whole grid rather than the neighbourhood, so they can't be cached per cell
+            rule_options
                 .iter()
-                .filter(|tile_option| {
-                    check_rule(
-                        &grid,
-                        history_grid,
-                        $find_pos,
-                        tile_to_rule(tile_option),
-                        unset.clone(),
-                        outer.clone(),
-                        max_depth,
-                    )
-                })
+                .filter(|&&option_i| count_allowed[option_i])
+                .map(|&option_i| &tile_options[option_i])
                 .collect::<Vec<_>>()
+        }};
+    }
+
+    // Places a tile without touching the option cache, only for tiles that are taken back out straight away
+    macro_rules! swap_tile {
+        ($set_pos: expr, $tile: expr) => {
+            let previous_tile = grid.get($set_pos, outer.clone());
+            if let Some(previous_count) = counts.get_mut(&previous_tile) {
+                *previous_count -= 1;
+            }
+            *counts.entry($tile.clone()).or_insert(0) += 1;
+            grid.set($set_pos, $tile);
         };
     }
 
-    while !front.is_empty()
-    /* && placed_stack.len() < grid.get_area() */
-    {
-        if clock_to_print <= 0 {
+    macro_rules! set_tile {
+        ($set_pos: expr, $tile: expr) => {
+            swap_tile!($set_pos, $tile);
+            grid_changes += 1;
+            for (dx, dy) in dependents.iter() {
+                let affected_pos = $set_pos.rel(*dx, *dy);
+                if grid.is_valid(&affected_pos) {
+                    option_cache[grid.pos_to_i(&affected_pos)] = None;
+                }
+            }
+        };
+    }
+
+    macro_rules! constraints_hold {
+        ($complete: expr) => {
+            check_constraints(&grid, &counts, constraints, unset.clone(), $complete)
+        };
+        // Only for when they held before the cell at `changed` was set, which every placement is checked for
+        (after $changed: expr) => {
+            check_constraints_after(&grid, &counts, constraints, unset.clone(), $changed)
+        };
+    }
+
+    // A count whose minimum is above its maximum can never be met, and would otherwise only be found out once the
+    // whole search had been exhausted
+    let bounds_cross = constraints
+        .iter()
+        .filter_map(|constraint| constraint.count_bounds(grid.get_area()))
+        .any(|(_, min, max)| min > max);
+
+    // Every later check only looks at what a placement changed, so this has to start out from a grid that passes
+    if bounds_cross || !constraints_hold!(false) {
+        observer(&grid, &SolverEvent::Failed);
+        return None;
+    }
+
+    // Takes the most recent placement back out, putting its cell back into the front
+    macro_rules! undo_last {
+        () => {
+            let (last_placed_pos, _, _) = placed_stack.pop().expect("Stack is empty but we just checked it");
+            ruled_out.retain(|(_, _, depth)| *depth <= placed_stack.len());
+            let last_placed_tile = grid.get(&last_placed_pos, outer.clone());
+            set_tile!(&last_placed_pos, unset.clone());
+            observer(
+                &grid,
+                &SolverEvent::Removed {
+                    pos: last_placed_pos.clone(),
+                    tile: last_placed_tile,
+                },
+            );
+
+            // let last_placed_options: Vec<&T> = find_valid_options!(&last_placed_pos);
+            // front.push(grid.pos_to_i(&last_placed_pos), last_placed_options.len());
+            front.push(grid.pos_to_i(&last_placed_pos), Reverse(0));
+        };
+    }
+
+    // Counts a dead end against max_backtracks, giving up once they are all used
+    let mut dead_ends = 0;
+    macro_rules! dead_end {
+        () => {
+            dead_ends += 1;
+            if max_backtracks.is_some_and(|max_backtracks| dead_ends > max_backtracks) {
+                observer(&grid, &SolverEvent::Failed);
+                return None;
+            }
+        };
+    }
+
+    // Undoes the most recent placement, and then keeps undoing placements for as long as they have run out of
+    // attempts
+    macro_rules! backtrack {
+        () => {
+            let mut first_backtrack = true;
+
+            loop {
+                if placed_stack.is_empty() {
+                    observer(&grid, &SolverEvent::Failed);
+                    return None; // We failed to generated anything
+                }
+
+                let (_, _, last_placed_attempts_remaining) = placed_stack
+                    .last()
+                    .expect("Stack is empty but we just checked it");
+
+                if first_backtrack || *last_placed_attempts_remaining == 0 {
+                    first_backtrack = false;
+                    undo_last!();
+                } else {
+                    placed_stack.last_mut().unwrap().2 = last_placed_attempts_remaining - 1;
+                    break;
+                }
+            }
+        };
+    }
+
+    // When a cell is left without any options, only the placements its rules read from can have caused it. Rather
+    // than undoing placements in order (which are often far away and had nothing to do with it), this jumps straight
+    // back to the most recent of those, undoing it along with everything placed since. Its tile is then ruled out of
+    // its cell until something placed before it is undone, as it is bound to leave the failed cell without options
+    // again.
+    // Constraints can depend on any cell, as can a cell that ran out of options because some were ruled out, so
+    // either of those fall back to undoing placements in order, as do rules that aren't monotone.
+    macro_rules! backjump {
+        ($failed_pos: expr, $had_ruled_out: expr) => {
+            let culprit = if monotone && constraints.is_empty() && !$had_ruled_out {
+                placed_stack.iter().rposition(|(placed_pos, _, _)| {
+                    footprint
+                        .binary_search(&(placed_pos.x - $failed_pos.x, placed_pos.y - $failed_pos.y))
+                        .is_ok()
+                })
+            } else {
+                None
+            };
+            match culprit {
+                Some(culprit) => {
+                    while placed_stack.len() > culprit + 1 {
+                        undo_last!();
+                    }
+                    let (culprit_pos, culprit_tile, _) = placed_stack.last().expect("The culprit is on the stack");
+                    let culprit_i = grid.pos_to_i(culprit_pos);
+                    let culprit_tile = culprit_tile.clone();
+                    undo_last!();
+                    ruled_out.push((culprit_i, culprit_tile, culprit));
+                }
+                None => {
+                    backtrack!();
+                }
+            }
+        };
+    }
+
+    // Cells before this index have all been set at some point. Any that have since been unset by backtracking have been
+    // put back into the front, so this only ever needs to move forward.
+    let mut next_unvisited = 0;
+
+    loop {
+        if front.is_empty() {
+            // Not every cell is necessarily reachable by following the footprint out from the seeds, so pick up
+            // wherever has been missed
+            while next_unvisited < grid.get_area() && grid.get_cells()[next_unvisited] != unset {
+                next_unvisited += 1;
+            }
+            if next_unvisited < grid.get_area() {
+                front.push(next_unvisited, Reverse(0));
+                continue;
+            }
+
+            // Everything has been set, the grid is only finished if it also satisfies every constraint
+            if constraints_hold!(true) {
+                break;
+            }
+            dead_end!();
+            backtrack!();
+            continue;
+        }
+
+        if clock_to_print == 0 {
             print!(
                 "Progress: {: >3}%\r",
                 ((100.0 / grid.get_area() as f32) * placed_stack.len() as f32) as u32
@@ -202,11 +789,21 @@ pub fn collapse_rule<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
         let pos = grid.i_to_pos(i);
 
         let current_tile = grid.get(&pos, outer.clone());
-        if current_tile != unset || current_tile == outer {
+        if current_tile != unset {
             continue;
         }
 
         let valid_options = find_valid_options!(&pos);
+        let options_before_ruled_out = valid_options.len();
+        let valid_options = valid_options
+            .into_iter()
+            .filter(|option| {
+                !ruled_out
+                    .iter()
+                    .any(|(ruled_i, tile, _)| *ruled_i == i && tile == *option)
+            })
+            .collect::<Vec<_>>();
+        let had_ruled_out = valid_options.len() < options_before_ruled_out;
 
         // We've re-calculated this tile's valid options, and only want to evaluate the lowest-"entropy" option
         if !front.is_empty() && valid_options.len() > front.peek().unwrap().1 .0 {
@@ -214,62 +811,75 @@ pub fn collapse_rule<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
             continue;
         }
 
-        if valid_options.is_empty() {
-            front.push(i, Reverse(0));
-
-            let mut first_backtrack = true;
+        // Global constraints are too expensive to check for every option, so options are drawn one at a time and only
+        // the one drawn is checked against them, drawing again from the rest if it breaks them
+        let mut valid_options = valid_options;
+        let chosen_option = loop {
+            if valid_options.is_empty() {
+                break None;
+            }
 
-            loop {
-                if placed_stack.is_empty() {
-                    return None; // We failed to generated anything
+            let drawn = if tile_weights.is_empty() {
+                rng.gen_range(0..valid_options.len())
+            } else {
+                let option_weights = valid_options
+                    .iter()
+                    .map(|option| tile_weights[tile_options.iter().position(|tile| tile == *option).unwrap()]);
+                match WeightedIndex::new(option_weights) {
+                    Ok(weighted) => weighted.sample(rng),
+                    // Every valid option has a weight of zero, so fall back to picking between them evenly
+                    Err(_) => rng.gen_range(0..valid_options.len()),
                 }
+            };
 
-                let (last_placed_pos, _, last_placed_attempts_remaining) =
-                    placed_stack.last().expect("Stack is empty but we just checked it");
-
-                if first_backtrack || *last_placed_attempts_remaining <= 0 {
-                    first_backtrack = false;
-                    grid.set(&last_placed_pos, unset.clone());
-
-                    // let last_placed_options: Vec<&T> = find_valid_options!(&last_placed_pos);
-                    // front.push(grid.pos_to_i(&last_placed_pos), last_placed_options.len());
-                    front.push(grid.pos_to_i(&last_placed_pos), Reverse(0));
-
-                    placed_stack.pop();
-                } else {
-                    placed_stack.last_mut().unwrap().2 = last_placed_attempts_remaining - 1;
-                    break;
-                }
+            if constraints.is_empty() {
+                break Some(valid_options[drawn]);
             }
-            continue;
-        }
+            swap_tile!(&pos, valid_options[drawn].clone());
+            let holds = constraints_hold!(after i);
+            swap_tile!(&pos, unset.clone());
+            if holds {
+                break Some(valid_options[drawn]);
+            }
+            valid_options.remove(drawn);
+        };
 
-        let chosen_option = valid_options[rng.gen_range(0..valid_options.len())];
+        let Some(chosen_option) = chosen_option else {
+            front.push(i, Reverse(0));
+            dead_end!();
+            backjump!(&pos, had_ruled_out);
+            continue;
+        };
 
-        grid.set(&pos, chosen_option.clone());
-        placed_stack.push((pos.clone(), chosen_option.clone(), 15));
+        set_tile!(&pos, chosen_option.clone());
+        observer(
+            &grid,
+            &SolverEvent::Placed {
+                pos: pos.clone(),
+                tile: chosen_option.clone(),
+            },
+        );
+        placed_stack.push((pos.clone(), chosen_option.clone(), backtrack_attempts));
 
-        // Now, for each unset neighbour within the re-check radius, we need to recheck it
-        for dx in (-re_check_radius)..=re_check_radius {
-            for dy in (-re_check_radius)..=re_check_radius {
-                if dx == 0 && dy == 0 {
-                    continue;
-                }
-                let neighbour_pos = pos.rel(dx, dy);
-                if !grid.is_valid(&neighbour_pos) {
-                    continue;
-                }
-                let neighbour_tile = grid.get(&neighbour_pos, outer.clone());
-                if neighbour_tile != unset || neighbour_tile == outer {
-                    continue;
-                }
-                // TODO: We should probably cache this somewhere and re-use it if no tiles within re_check_radius have changed (using a counter comparison?)
-                let neighbour_valid_options = find_valid_options!(&neighbour_pos);
-                front.push(grid.pos_to_i(&neighbour_pos), Reverse(neighbour_valid_options.len()));
-                // front.push(grid.pos_to_i(&neighbour_pos), Reverse(0));
+        // Now, for each unset neighbour whose rules read this cell, we need to recheck it
+        for (dx, dy) in dependents.iter() {
+            if *dx == 0 && *dy == 0 {
+                continue;
+            }
+            let neighbour_pos = pos.rel(*dx, *dy);
+            if !grid.is_valid(&neighbour_pos) {
+                continue;
+            }
+            let neighbour_tile = grid.get(&neighbour_pos, outer.clone());
+            if neighbour_tile != unset {
+                continue;
             }
+            let neighbour_valid_options = find_valid_options!(&neighbour_pos);
+            front.push(grid.pos_to_i(&neighbour_pos), Reverse(neighbour_valid_options.len()));
+            // front.push(grid.pos_to_i(&neighbour_pos), Reverse(0));
         }
     }
 
+    observer(&grid, &SolverEvent::Finished);
     Some(grid)
 }
@@ -0,0 +1,220 @@
+use super::collapse_rules::{CollapseRule, RulePredicate, Truth};
+use super::grid::Grid;
+use super::pos::Pos;
+use std::hash::Hash;
+
+// A flattened form of a CollapseRule, built once by `compile_rule` and then evaluated many times by
+// `evaluate_compiled_rule` with the same results as `evaluate_rule`:
+// + True/False are folded away into their parents
+// + Nested And/Or/Parenthesis are merged into a single list
+// + Every direction (Left, Near, NextTo, ...) becomes a list of offsets, with nested directions summed together
+// + The max_depth limit is applied once at compile time
+pub enum CompiledRule<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    True,
+    False,
+    Unknown,
+    And(Vec<CompiledRule<T>>),
+    Or(Vec<CompiledRule<T>>),
+    Not(Box<CompiledRule<T>>),
+
+    Is(T),
+    Was(T),
+    InBounds,
+    Unset,
+    Outer,
+    Custom(RulePredicate<T>),
+
+    // The rule must pass at every one of these offsets
+    All(Vec<(isize, isize)>, Box<CompiledRule<T>>),
+    // The rule must pass at one or more of these offsets
+    Any(Vec<(isize, isize)>, Box<CompiledRule<T>>),
+}
+
+pub fn compile_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    rule: &CollapseRule<T>,
+    max_depth: usize,
+) -> CompiledRule<T> {
+    if max_depth == 0 {
+        return CompiledRule::False;
+    }
+
+    let sub_compile = |sub_rule: &CollapseRule<T>| compile_rule(sub_rule, max_depth - 1);
+    let at = |dx: isize, dy: isize, sub_rule: &CollapseRule<T>| all_at(vec![(dx, dy)], sub_compile(sub_rule));
+    let next_to = [(0, 1), (0, -1), (1, 0), (-1, 0)];
+
+    match rule {
+        CollapseRule::And(sub_rules) => and(sub_rules.iter().map(sub_compile).collect()),
+        CollapseRule::Or(sub_rules) => or(sub_rules.iter().map(sub_compile).collect()),
+        CollapseRule::Not(sub_rule) => match sub_compile(sub_rule) {
+            CompiledRule::True => CompiledRule::False,
+            CompiledRule::False => CompiledRule::True,
+            CompiledRule::Unknown => CompiledRule::Unknown,
+            CompiledRule::Not(inner) => *inner,
+            compiled => CompiledRule::Not(Box::new(compiled)),
+        },
+        CollapseRule::Is(tile_type) => CompiledRule::Is(tile_type.clone()),
+        CollapseRule::Was(tile_type) => CompiledRule::Was(tile_type.clone()),
+        CollapseRule::Left(sub_rule) => at(-1, 0, sub_rule),
+        CollapseRule::Right(sub_rule) => at(1, 0, sub_rule),
+        CollapseRule::Up(sub_rule) => at(0, 1, sub_rule),
+        CollapseRule::Down(sub_rule) => at(0, -1, sub_rule),
+        CollapseRule::UpLeft(sub_rule) => at(-1, 1, sub_rule),
+        CollapseRule::UpRight(sub_rule) => at(1, 1, sub_rule),
+        CollapseRule::DownLeft(sub_rule) => at(-1, -1, sub_rule),
+        CollapseRule::DownRight(sub_rule) => at(1, -1, sub_rule),
+        CollapseRule::Near(sub_rule, radius) => {
+            let offsets = ((-radius)..=*radius)
+                .flat_map(|dx| ((-radius)..=*radius).map(move |dy| (dx, dy)))
+                .filter(|&offset| offset != (0, 0))
+                .collect();
+            all_at(offsets, sub_compile(sub_rule))
+        }
+        CollapseRule::NextTo(sub_rule) => all_at(next_to.to_vec(), sub_compile(sub_rule)),
+        CollapseRule::NextTo1(sub_rule) => any_at(next_to.to_vec(), sub_compile(sub_rule)),
+        CollapseRule::Parenthesis(sub_rule) => sub_compile(sub_rule),
+        CollapseRule::True => CompiledRule::True,
+        CollapseRule::False => CompiledRule::False,
+        CollapseRule::InBounds => CompiledRule::InBounds,
+        CollapseRule::Unset => CompiledRule::Unset,
+        CollapseRule::Outer => CompiledRule::Outer,
+        CollapseRule::Custom(predicate, _) => CompiledRule::Custom(predicate.clone()),
+        CollapseRule::InLayer(_, _) => CompiledRule::Unknown,
+    }
+}
+
+fn and<T: PartialEq + Eq + Hash + Clone + Sync + Send>(sub_rules: Vec<CompiledRule<T>>) -> CompiledRule<T> {
+    let mut flattened = vec![];
+    for sub_rule in sub_rules {
+        match sub_rule {
+            CompiledRule::True => {}
+            CompiledRule::False => return CompiledRule::False,
+            CompiledRule::And(inner) => flattened.extend(inner),
+            sub_rule => flattened.push(sub_rule),
+        }
+    }
+    match flattened.len() {
+        0 => CompiledRule::True,
+        1 => flattened.pop().unwrap(),
+        _ => CompiledRule::And(flattened),
+    }
+}
+
+fn or<T: PartialEq + Eq + Hash + Clone + Sync + Send>(sub_rules: Vec<CompiledRule<T>>) -> CompiledRule<T> {
+    let mut flattened = vec![];
+    for sub_rule in sub_rules {
+        match sub_rule {
+            CompiledRule::False => {}
+            CompiledRule::True => return CompiledRule::True,
+            CompiledRule::Or(inner) => flattened.extend(inner),
+            sub_rule => flattened.push(sub_rule),
+        }
+    }
+    match flattened.len() {
+        0 => CompiledRule::False,
+        1 => flattened.pop().unwrap(),
+        _ => CompiledRule::Or(flattened),
+    }
+}
+
+// Every pairing of an outer and inner offset, for a direction nested within another
+fn sum_offsets(outer: &[(isize, isize)], inner: &[(isize, isize)]) -> Vec<(isize, isize)> {
+    outer
+        .iter()
+        .flat_map(|(odx, ody)| inner.iter().map(move |(idx, idy)| (odx + idx, ody + idy)))
+        .collect()
+}
+
+fn all_at<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    offsets: Vec<(isize, isize)>,
+    sub_rule: CompiledRule<T>,
+) -> CompiledRule<T> {
+    match sub_rule {
+        _ if offsets.is_empty() => CompiledRule::True,
+        CompiledRule::True => CompiledRule::True,
+        CompiledRule::False => CompiledRule::False,
+        CompiledRule::Unknown => CompiledRule::Unknown,
+        CompiledRule::All(inner_offsets, inner) => CompiledRule::All(sum_offsets(&offsets, &inner_offsets), inner),
+        // With only the one offset there is nothing for All to combine, so it can be pushed into the inner Any
+        CompiledRule::Any(inner_offsets, inner) if offsets.len() == 1 => {
+            CompiledRule::Any(sum_offsets(&offsets, &inner_offsets), inner)
+        }
+        sub_rule => CompiledRule::All(offsets, Box::new(sub_rule)),
+    }
+}
+
+fn any_at<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    offsets: Vec<(isize, isize)>,
+    sub_rule: CompiledRule<T>,
+) -> CompiledRule<T> {
+    match sub_rule {
+        _ if offsets.is_empty() => CompiledRule::False,
+        CompiledRule::True => CompiledRule::True,
+        CompiledRule::False => CompiledRule::False,
+        CompiledRule::Unknown => CompiledRule::Unknown,
+        CompiledRule::Any(inner_offsets, inner) => CompiledRule::Any(sum_offsets(&offsets, &inner_offsets), inner),
+        CompiledRule::All(inner_offsets, inner) if inner_offsets.len() == 1 => {
+            CompiledRule::Any(sum_offsets(&offsets, &inner_offsets), inner)
+        }
+        sub_rule => CompiledRule::Any(offsets, Box::new(sub_rule)),
+    }
+}
+
+// Counterpart to check_rule
+pub fn check_compiled_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    history_grid: &Grid<T>,
+    pos: &Pos,
+    rule: &CompiledRule<T>,
+    unset: &T,
+    outer: &T,
+) -> bool {
+    evaluate_compiled_rule(grid, history_grid, pos, rule, unset, outer) != Truth::False
+}
+
+pub fn evaluate_compiled_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    history_grid: &Grid<T>,
+    pos: &Pos,
+    rule: &CompiledRule<T>,
+    unset: &T,
+    outer: &T,
+) -> Truth {
+    macro_rules! sub_check_rule {
+        ($sub_pos:expr, $sub_rule:expr) => {
+            evaluate_compiled_rule(grid, history_grid, $sub_pos, $sub_rule, unset, outer)
+        };
+    }
+
+    let read = |tile: &T, tile_type: &T| {
+        if tile == unset {
+            Truth::Unknown
+        } else {
+            Truth::from(tile == tile_type)
+        }
+    };
+
+    match rule {
+        CompiledRule::True => Truth::True,
+        CompiledRule::False => Truth::False,
+        CompiledRule::Unknown => Truth::Unknown,
+        CompiledRule::And(sub_rules) => Truth::all(sub_rules.iter().map(|sub_rule| sub_check_rule!(pos, sub_rule))),
+        CompiledRule::Or(sub_rules) => Truth::any(sub_rules.iter().map(|sub_rule| sub_check_rule!(pos, sub_rule))),
+        CompiledRule::Not(sub_rule) => !sub_check_rule!(pos, sub_rule),
+        CompiledRule::Is(tile_type) => read(grid.get_ref(pos, outer), tile_type),
+        CompiledRule::Was(tile_type) => read(history_grid.get_ref(pos, outer), tile_type),
+        CompiledRule::InBounds => Truth::from(grid.is_valid(pos)),
+        CompiledRule::Unset => Truth::from(grid.get_ref(pos, outer) == unset),
+        CompiledRule::Outer => Truth::from(!grid.is_valid(pos)),
+        CompiledRule::Custom(predicate) => Truth::from(predicate(grid, history_grid, pos)),
+        CompiledRule::All(offsets, sub_rule) => Truth::all(
+            offsets
+                .iter()
+                .map(|(dx, dy)| sub_check_rule!(&pos.rel(*dx, *dy), sub_rule)),
+        ),
+        CompiledRule::Any(offsets, sub_rule) => Truth::any(
+            offsets
+                .iter()
+                .map(|(dx, dy)| sub_check_rule!(&pos.rel(*dx, *dy), sub_rule)),
+        ),
+    }
+}
@@ -0,0 +1,400 @@
+use super::grid::Grid;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+// Constraints over the whole grid rather than a single cell's neighbourhood. These are checked by `collapse_rule`
+// against each option for the cell it is about to place (where they only reject grids that can no longer be
+// completed) and again once every cell has been set (where they must hold outright), backtracking in either case.
+pub enum GlobalConstraint<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    // The number of cells holding `tile` must fall within min..=max
+    Count { tile: T, min: usize, max: usize },
+    // Same as Count, but expressed as a fraction (0.0 - 1.0) of the grid's area
+    Fraction { tile: T, min: f32, max: f32 },
+    // Every cell holding one of the walkable tiles must be reachable from every other by stepping up, down, left or
+    // right across walkable cells
+    Connected { walkable: Vec<T> },
+    // Every cell holding one of `tiles` must be reachable from every other across walkable cells, the `tiles`
+    // themselves are always treated as walkable
+    Reachable { walkable: Vec<T>, tiles: Vec<T> },
+    // Every `from` cell must have a walkable path to every `to` cell, with the shortest such path taking between
+    // min..=max steps
+    PathLength { walkable: Vec<T>, from: T, to: T, min: usize, max: usize },
+    // There must be a walkable path from a cell along one edge of the grid to a cell along another
+    EdgePath { walkable: Vec<T>, from: Edge, to: Edge },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Edge {
+    Top,    // The row at y = 0
+    Bottom, // The row at y = height - 1
+    Left,   // The column at x = 0
+    Right,  // The column at x = width - 1
+}
+
+impl Edge {
+    // The indices of every cell along this edge of the grid
+    pub fn cells<T: PartialEq + Eq + Hash + Clone + Sync + Send>(&self, grid: &Grid<T>) -> Vec<usize> {
+        let (width, height) = (grid.get_width(), grid.get_height());
+        match self {
+            Edge::Top => (0..width).collect(),
+            Edge::Bottom => (0..width).map(|x| (height - 1) * width + x).collect(),
+            Edge::Left => (0..height).map(|y| y * width).collect(),
+            Edge::Right => (0..height).map(|y| y * width + width - 1).collect(),
+        }
+    }
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> GlobalConstraint<T> {
+    // Resolves the constraint to an absolute (tile, min, max) count for a grid with the given area, if it is a
+    // cardinality constraint
+    pub fn count_bounds(&self, area: usize) -> Option<(&T, usize, usize)> {
+        match self {
+            GlobalConstraint::Count { tile, min, max } => Some((tile, *min, *max)),
+            GlobalConstraint::Fraction { tile, min, max } => Some((
+                tile,
+                fraction_of(*min, area).ceil() as usize,
+                fraction_of(*max, area).floor() as usize,
+            )),
+            _ => None,
+        }
+    }
+}
+
+// The number of cells a fraction of the area comes to. This is worked out in f64 and snapped to a whole number when it
+// lands within rounding error of one, otherwise 0.3 of 100 cells comes out a hair over 30 and is rounded up to 31.
+fn fraction_of(fraction: f32, area: usize) -> f64 {
+    let cells = fraction as f64 * area as f64;
+    let epsilon = f32::EPSILON as f64 * (area as f64).max(1.0);
+    match (cells - cells.round()).abs() <= epsilon {
+        true => cells.round(),
+        false => cells,
+    }
+}
+
+// Counts the number of cells holding each distinct tile
+pub fn tile_counts<T: PartialEq + Eq + Hash + Clone + Sync + Send>(grid: &Grid<T>) -> HashMap<T, usize> {
+    let mut counts = HashMap::new();
+    for tile in grid.get_cells() {
+        *counts.entry(tile.clone()).or_insert(0) += 1;
+    }
+    counts
+}
+
+// The number of unset cells that still have to be given to particular tiles for every cardinality constraint to reach
+// its minimum, optionally after placing one more `placing` tile
+fn required_cells<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    tile_counts: &HashMap<T, usize>,
+    constraints: &[GlobalConstraint<T>],
+    placing: Option<&T>,
+) -> usize {
+    let mut required = HashMap::<&T, usize>::new();
+    for (tile, min, _) in constraints.iter().filter_map(|c| c.count_bounds(grid.get_area())) {
+        let count = tile_counts.get(tile).copied().unwrap_or(0) + usize::from(placing == Some(tile));
+        let tile_required = required.entry(tile).or_insert(0);
+        *tile_required = (*tile_required).max(min.saturating_sub(count));
+    }
+    required.values().sum()
+}
+
+// Returns false if placing one more `tile` into an unset cell would immediately break the cardinality constraints, so
+// the solver can discard the option without having to place it and backtrack
+pub fn constraints_allow<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    tile_counts: &HashMap<T, usize>,
+    constraints: &[GlobalConstraint<T>],
+    tile: &T,
+    unset: T,
+) -> bool {
+    let count_of = |tile: &T| tile_counts.get(tile).copied().unwrap_or(0);
+
+    let at_max = constraints
+        .iter()
+        .filter_map(|constraint| constraint.count_bounds(grid.get_area()))
+        .any(|(constraint_tile, _, max)| constraint_tile == tile && count_of(tile) >= max);
+
+    // Using up an unset cell must still leave enough room for every other tile to reach its minimum
+    !at_max && required_cells(grid, tile_counts, constraints, Some(tile)) < count_of(&unset)
+}
+
+// Checks every constraint, along with whether there is still room for all of their minimum counts at once
+pub fn check_constraints<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    tile_counts: &HashMap<T, usize>,
+    constraints: &[GlobalConstraint<T>],
+    unset: T,
+    complete: bool,
+) -> bool {
+    let remaining = tile_counts.get(&unset).copied().unwrap_or(0);
+
+    (complete || required_cells(grid, tile_counts, constraints, None) <= remaining)
+        && constraints
+            .iter()
+            .all(|constraint| check_constraint(grid, tile_counts, constraint, unset.clone(), complete))
+}
+
+// Same as check_constraints on a grid that is still being solved, for when the grid passed them before the unset cell
+// at `changed` was set. Setting one cell can only split up the walkable area around that cell, so most of the time
+// only that area has to be walked rather than the whole grid.
+pub fn check_constraints_after<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    tile_counts: &HashMap<T, usize>,
+    constraints: &[GlobalConstraint<T>],
+    unset: T,
+    changed: usize,
+) -> bool {
+    let remaining = tile_counts.get(&unset).copied().unwrap_or(0);
+
+    required_cells(grid, tile_counts, constraints, None) <= remaining
+        && constraints
+            .iter()
+            .all(|constraint| check_constraint_after(grid, tile_counts, constraint, unset.clone(), changed))
+}
+
+fn check_constraint_after<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    tile_counts: &HashMap<T, usize>,
+    constraint: &GlobalConstraint<T>,
+    unset: T,
+    changed: usize,
+) -> bool {
+    let cells = grid.get_cells();
+    let tile = &cells[changed];
+    let passable = |tile: &T, walkable: &[T]| walkable.contains(tile) || *tile == unset;
+
+    // The cell was unset, so could be walked across before. If it still can be, the only thing that can have gone
+    // wrong is a new target turning up somewhere cut off from the others, otherwise the walkable area has lost a cell
+    // and could have been split in two.
+    let connected_after = |is_target: &dyn Fn(&T) -> bool, passable: &dyn Fn(&T) -> bool| match passable(tile) {
+        true => !is_target(tile) || search(grid, changed, passable, |i| i != changed && is_target(&cells[i])),
+        false => still_joined(grid, changed, passable),
+    };
+
+    let holds = match constraint {
+        GlobalConstraint::Connected { walkable } => {
+            connected_after(&|tile| walkable.contains(tile), &|tile| passable(tile, walkable))
+        }
+        GlobalConstraint::Reachable { walkable, tiles } => connected_after(&|tile| tiles.contains(tile), &|tile| {
+            tiles.contains(tile) || passable(tile, walkable)
+        }),
+        // A cell along either edge could have been the path's only way on or off it
+        GlobalConstraint::EdgePath { walkable, from, to } => {
+            !from.cells(grid).contains(&changed)
+                && !to.cells(grid).contains(&changed)
+                && connected_after(&|_| false, &|tile| passable(tile, walkable))
+        }
+        _ => false,
+    };
+
+    // Anything that can't be settled around the cell is checked over the whole grid
+    holds || check_constraint(grid, tile_counts, constraint, unset, false)
+}
+
+// | complete
+// + When false, any 'unset' cells are treated as still being able to become anything, so this only fails if the
+// + constraint can no longer be satisfied however the rest of the grid is filled in.
+// + When true, the grid is taken as-is.
+pub fn check_constraint<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    tile_counts: &HashMap<T, usize>,
+    constraint: &GlobalConstraint<T>,
+    unset: T,
+    complete: bool,
+) -> bool {
+    let count_of = |tile: &T| tile_counts.get(tile).copied().unwrap_or(0);
+
+    if let Some((tile, min, max)) = constraint.count_bounds(grid.get_area()) {
+        let count = count_of(tile);
+        let remaining = if complete { 0 } else { count_of(&unset) };
+        return count <= max && count + remaining >= min;
+    }
+
+    // While solving, an unset cell could still turn out to be walkable
+    let passable = |tile: &T, walkable: &[T]| walkable.contains(tile) || (!complete && *tile == unset);
+
+    match constraint {
+        GlobalConstraint::Connected { walkable } => {
+            all_connected(grid, |tile| walkable.contains(tile), |tile| passable(tile, walkable))
+        }
+        GlobalConstraint::Reachable { walkable, tiles } => all_connected(
+            grid,
+            |tile| tiles.contains(tile),
+            |tile| tiles.contains(tile) || passable(tile, walkable),
+        ),
+        GlobalConstraint::PathLength {
+            walkable,
+            from,
+            to,
+            min,
+            max,
+        } => {
+            let cells = grid.get_cells();
+            let starts = (0..grid.get_area()).filter(|&i| cells[i] == *from).collect::<Vec<_>>();
+            let ends = (0..grid.get_area()).filter(|&i| cells[i] == *to).collect::<Vec<_>>();
+
+            if complete && (starts.is_empty() || ends.is_empty()) {
+                return false;
+            }
+
+            // Filling in the rest of the grid can only ever open up shortcuts, so walking across just the cells that
+            // have been set gives the longest each path can end up being, while also walking across unset cells gives
+            // the shortest. Once the grid is complete these are one and the same.
+            let path_tile = |tile: &T| *tile == *from || *tile == *to || walkable.contains(tile);
+            // Only distances up to min and max matter, so neither walk has to go any further than that
+            let longest = flood_fill_within(grid, &starts, path_tile, min.saturating_sub(1));
+            if ends.iter().any(|&i| longest[i].is_some_and(|distance| distance < *min)) {
+                return false;
+            }
+
+            // If one side of the path has not been placed yet it could still turn up on any unset cell
+            let within_max = |origin: usize, targets: &[usize]| {
+                let shortest = flood_fill_within(
+                    grid,
+                    &[origin],
+                    |tile| path_tile(tile) || passable(tile, walkable),
+                    *max,
+                );
+                let reached = |i: usize| shortest[i].is_some_and(|distance| distance <= *max);
+                match targets.is_empty() {
+                    true => (0..grid.get_area()).any(|i| cells[i] == unset && reached(i)),
+                    false => targets.iter().all(|&i| reached(i)),
+                }
+            };
+
+            starts.iter().all(|&start| within_max(start, &ends))
+                && (!starts.is_empty() || ends.iter().all(|&end| within_max(end, &starts)))
+        }
+        GlobalConstraint::EdgePath { walkable, from, to } => {
+            let cells = grid.get_cells();
+            let starts = from
+                .cells(grid)
+                .into_iter()
+                .filter(|&i| passable(&cells[i], walkable))
+                .collect::<Vec<_>>();
+            let distances = flood_fill(grid, &starts, |tile| passable(tile, walkable));
+            to.cells(grid).into_iter().any(|i| distances[i].is_some())
+        }
+        _ => true,
+    }
+}
+
+// Returns true if every cell accepted by `is_target` lies within a single region of `passable` cells
+fn all_connected<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    is_target: impl Fn(&T) -> bool,
+    passable: impl Fn(&T) -> bool,
+) -> bool {
+    let targets = (0..grid.get_area())
+        .filter(|&i| is_target(&grid.get_cells()[i]))
+        .collect::<Vec<_>>();
+
+    match targets.first() {
+        Some(&start) => {
+            let distances = flood_fill(grid, &[start], passable);
+            targets.iter().all(|&i| distances[i].is_some())
+        }
+        None => true,
+    }
+}
+
+// Walks outward from every start cell, stepping up, down, left or right into cells that `passable` accepts, returning
+// the number of steps needed to reach each cell (or None if it cannot be reached)
+pub fn flood_fill<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    starts: &[usize],
+    passable: impl Fn(&T) -> bool,
+) -> Vec<Option<usize>> {
+    flood_fill_within(grid, starts, passable, usize::MAX)
+}
+
+// Same as flood_fill, but stops walking once cells are `max_distance` steps away, leaving anything further as None
+pub fn flood_fill_within<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    starts: &[usize],
+    passable: impl Fn(&T) -> bool,
+    max_distance: usize,
+) -> Vec<Option<usize>> {
+    let mut distances = vec![None; grid.get_area()];
+    let mut open = VecDeque::new();
+
+    for &start in starts {
+        if distances[start].is_none() {
+            distances[start] = Some(0);
+            open.push_back(start);
+        }
+    }
+
+    while let Some(i) = open.pop_front() {
+        let distance = distances[i].expect("Only reached cells are queued");
+        if distance >= max_distance {
+            continue;
+        }
+
+        for neighbour_i in neighbours(grid, i) {
+            if distances[neighbour_i].is_none() && passable(&grid.get_cells()[neighbour_i]) {
+                distances[neighbour_i] = Some(distance + 1);
+                open.push_back(neighbour_i);
+            }
+        }
+    }
+
+    distances
+}
+
+// Walks outward from `start` like flood_fill, stopping as soon as `found` accepts one of the cells reached. Returns
+// whether it did.
+fn search<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    start: usize,
+    passable: impl Fn(&T) -> bool,
+    mut found: impl FnMut(usize) -> bool,
+) -> bool {
+    let mut reached = vec![false; grid.get_area()];
+    let mut open = VecDeque::from([start]);
+    reached[start] = true;
+
+    while let Some(i) = open.pop_front() {
+        if found(i) {
+            return true;
+        }
+        for neighbour_i in neighbours(grid, i) {
+            if !reached[neighbour_i] && passable(&grid.get_cells()[neighbour_i]) {
+                reached[neighbour_i] = true;
+                open.push_back(neighbour_i);
+            }
+        }
+    }
+    false
+}
+
+// Returns true if the passable cells next to `i` can all still reach each other without going through `i`, in which
+// case blocking `i` off hasn't cut any region in two
+fn still_joined<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    i: usize,
+    passable: impl Fn(&T) -> bool,
+) -> bool {
+    let mut unreached = neighbours(grid, i)
+        .filter(|&neighbour_i| passable(&grid.get_cells()[neighbour_i]))
+        .collect::<Vec<_>>();
+    let Some(first) = unreached.pop() else {
+        return true;
+    };
+    unreached.is_empty()
+        || search(grid, first, &passable, |reached_i| {
+            unreached.retain(|&neighbour_i| neighbour_i != reached_i);
+            unreached.is_empty()
+        })
+}
+
+// The cells directly up, down, left and right of `i` that lie within the grid
+fn neighbours<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    i: usize,
+) -> impl Iterator<Item = usize> + '_ {
+    let pos = grid.i_to_pos(i);
+    [pos.rel(0, 1), pos.rel(0, -1), pos.rel(1, 0), pos.rel(-1, 0)]
+        .into_iter()
+        .filter(|neighbour_pos| grid.is_valid(neighbour_pos))
+        .map(|neighbour_pos| grid.pos_to_i(&neighbour_pos))
+}
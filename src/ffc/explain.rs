@@ -0,0 +1,222 @@
+use super::collapse_rules::{CollapseRule, Truth};
+use super::grid::Grid;
+use super::pos::Pos;
+use std::fmt;
+use std::hash::Hash;
+
+// The evaluation of one rule (and everything beneath it) at one position, as returned by explain_rule
+#[derive(Debug, Clone)]
+pub struct RuleTrace<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    pub rule: &'static str,
+    // The tile being looked for by Is and Was
+    pub tile: Option<T>,
+    pub pos: Pos,
+    pub result: Truth,
+    // Set if max_depth ran out before this rule could be evaluated, in which case it fails
+    pub out_of_depth: bool,
+    // The cells this rule read itself (not including those read by sub-rules), along with what they held
+    pub inspected: Vec<(Pos, T)>,
+    pub children: Vec<RuleTrace<T>>,
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> RuleTrace<T> {
+    // The deepest rules that failed, that is those that failed without any of their own sub-rules failing. These are
+    // the clauses to point at when explaining why a tile was rejected.
+    pub fn failures(&self) -> Vec<&RuleTrace<T>> {
+        if self.result != Truth::False {
+            return vec![];
+        }
+        let child_failures = self
+            .children
+            .iter()
+            .flat_map(|child| child.failures())
+            .collect::<Vec<_>>();
+        if child_failures.is_empty() {
+            vec![self]
+        } else {
+            child_failures
+        }
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result
+    where
+        T: fmt::Debug,
+    {
+        write!(f, "{:indent$}{}", "", self.rule, indent = indent * 2)?;
+        if let Some(tile) = &self.tile {
+            write!(f, "({tile:?})")?;
+        }
+        write!(f, " at ({}, {}) -> {:?}", self.pos.x, self.pos.y, self.result)?;
+        if self.out_of_depth {
+            write!(f, " [max_depth reached]")?;
+        }
+        for (pos, tile) in self.inspected.iter() {
+            write!(f, " [({}, {}) is {tile:?}]", pos.x, pos.y)?;
+        }
+        writeln!(f)?;
+        for child in self.children.iter() {
+            child.fmt_indented(f, indent + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send + fmt::Debug> fmt::Display for RuleTrace<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+// Evaluates the rule the same way as evaluate_rule, but records every sub-rule along the way. Unlike evaluate_rule
+// this never stops early, so every clause shows up in the trace even once the result is already known.
+pub fn explain_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    history_grid: &Grid<T>,
+    pos: &Pos,
+    rule: &CollapseRule<T>,
+    unset: T,
+    outer: T,
+    max_depth: usize,
+) -> RuleTrace<T> {
+    let mut trace = RuleTrace {
+        rule: rule_name(rule),
+        tile: match rule {
+            CollapseRule::Is(tile_type) | CollapseRule::Was(tile_type) => Some(tile_type.clone()),
+            _ => None,
+        },
+        pos: pos.clone(),
+        result: Truth::False,
+        out_of_depth: max_depth == 0,
+        inspected: vec![],
+        children: vec![],
+    };
+
+    if max_depth == 0 {
+        return trace;
+    }
+
+    let mut explain_at = |dx: isize, dy: isize, sub_rule: &CollapseRule<T>| {
+        let sub_trace = explain_rule(
+            grid,
+            history_grid,
+            &pos.rel(dx, dy),
+            sub_rule,
+            unset.clone(),
+            outer.clone(),
+            max_depth - 1,
+        );
+        let result = sub_trace.result;
+        trace.children.push(sub_trace);
+        result
+    };
+
+    let read = |tile: &T, tile_type: &T| {
+        if *tile == unset {
+            Truth::Unknown
+        } else {
+            Truth::from(tile == tile_type)
+        }
+    };
+
+    let next_to = [(0, 1), (0, -1), (1, 0), (-1, 0)];
+
+    let result = match rule {
+        CollapseRule::And(sub_rules) => Truth::all(
+            sub_rules
+                .iter()
+                .map(|sub_rule| explain_at(0, 0, sub_rule))
+                .collect::<Vec<_>>(),
+        ),
+        CollapseRule::Or(sub_rules) => Truth::any(
+            sub_rules
+                .iter()
+                .map(|sub_rule| explain_at(0, 0, sub_rule))
+                .collect::<Vec<_>>(),
+        ),
+        CollapseRule::Not(sub_rule) => !explain_at(0, 0, sub_rule),
+        CollapseRule::Left(sub_rule) => explain_at(-1, 0, sub_rule),
+        CollapseRule::Right(sub_rule) => explain_at(1, 0, sub_rule),
+        CollapseRule::Up(sub_rule) => explain_at(0, 1, sub_rule),
+        CollapseRule::Down(sub_rule) => explain_at(0, -1, sub_rule),
+        CollapseRule::UpLeft(sub_rule) => explain_at(-1, 1, sub_rule),
+        CollapseRule::UpRight(sub_rule) => explain_at(1, 1, sub_rule),
+        CollapseRule::DownLeft(sub_rule) => explain_at(-1, -1, sub_rule),
+        CollapseRule::DownRight(sub_rule) => explain_at(1, -1, sub_rule),
+        CollapseRule::Near(sub_rule, radius) => Truth::all(
+            ((-radius)..=*radius)
+                .flat_map(|dx| ((-radius)..=*radius).map(move |dy| (dx, dy)))
+                .filter(|&(dx, dy)| dx != 0 || dy != 0)
+                .map(|(dx, dy)| explain_at(dx, dy, sub_rule))
+                .collect::<Vec<_>>(),
+        ),
+        CollapseRule::NextTo(sub_rule) => Truth::all(
+            next_to
+                .iter()
+                .map(|&(dx, dy)| explain_at(dx, dy, sub_rule))
+                .collect::<Vec<_>>(),
+        ),
+        CollapseRule::NextTo1(sub_rule) => Truth::any(
+            next_to
+                .iter()
+                .map(|&(dx, dy)| explain_at(dx, dy, sub_rule))
+                .collect::<Vec<_>>(),
+        ),
+        CollapseRule::Parenthesis(sub_rule) => explain_at(0, 0, sub_rule),
+        CollapseRule::True => Truth::True,
+        CollapseRule::False => Truth::False,
+        CollapseRule::Is(tile_type) => {
+            let tile = grid.get(pos, outer.clone());
+            let result = read(&tile, tile_type);
+            trace.inspected.push((pos.clone(), tile));
+            result
+        }
+        CollapseRule::Was(tile_type) => {
+            let tile = history_grid.get(pos, outer.clone());
+            let result = read(&tile, tile_type);
+            trace.inspected.push((pos.clone(), tile));
+            result
+        }
+        CollapseRule::Unset => {
+            let tile = grid.get(pos, outer.clone());
+            let result = Truth::from(tile == unset);
+            trace.inspected.push((pos.clone(), tile));
+            result
+        }
+        CollapseRule::InBounds => Truth::from(grid.is_valid(pos)),
+        CollapseRule::Outer => Truth::from(!grid.is_valid(pos)),
+        CollapseRule::Custom(predicate, _) => Truth::from(predicate(grid, history_grid, pos)),
+        CollapseRule::InLayer(_, _) => Truth::Unknown,
+    };
+
+    trace.result = result;
+    trace
+}
+
+fn rule_name<T: PartialEq + Eq + Hash + Clone + Sync + Send>(rule: &CollapseRule<T>) -> &'static str {
+    match rule {
+        CollapseRule::And(_) => "And",
+        CollapseRule::Or(_) => "Or",
+        CollapseRule::Not(_) => "Not",
+        CollapseRule::Is(_) => "Is",
+        CollapseRule::Was(_) => "Was",
+        CollapseRule::Near(_, _) => "Near",
+        CollapseRule::NextTo(_) => "NextTo",
+        CollapseRule::NextTo1(_) => "NextTo1",
+        CollapseRule::Left(_) => "Left",
+        CollapseRule::Right(_) => "Right",
+        CollapseRule::Up(_) => "Up",
+        CollapseRule::Down(_) => "Down",
+        CollapseRule::UpLeft(_) => "UpLeft",
+        CollapseRule::UpRight(_) => "UpRight",
+        CollapseRule::DownLeft(_) => "DownLeft",
+        CollapseRule::DownRight(_) => "DownRight",
+        CollapseRule::Parenthesis(_) => "Parenthesis",
+        CollapseRule::True => "True",
+        CollapseRule::False => "False",
+        CollapseRule::InBounds => "InBounds",
+        CollapseRule::Unset => "Unset",
+        CollapseRule::Outer => "Outer",
+        CollapseRule::Custom(_, _) => "Custom",
+        CollapseRule::InLayer(_, _) => "InLayer",
+    }
+}
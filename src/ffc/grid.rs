@@ -33,7 +33,16 @@ impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> Grid<T> {
             self.grid
                 .get(self.pos_to_i(pos))
                 .map(|v| v.to_owned())
-                .unwrap_or_else(|| (&outer.clone()).to_owned())
+                .unwrap_or_else(|| outer.clone())
+        } else {
+            outer
+        }
+    }
+
+    // Same as get, but without cloning the tile
+    pub fn get_ref<'a>(&'a self, pos: &Pos, outer: &'a T) -> &'a T {
+        if self.is_valid(pos) {
+            self.grid.get(self.pos_to_i(pos)).unwrap_or(outer)
         } else {
             outer
         }
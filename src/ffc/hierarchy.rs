@@ -0,0 +1,265 @@
+use super::collapse_rules::{
+    check_rule, collapse_rule, rule_footprint, CollapseRule, RulePredicate, BACKTRACK_ATTEMPTS,
+};
+use super::grid::Grid;
+use super::pos::Pos;
+use std::hash::Hash;
+use std::sync::Arc;
+
+// Coarse-to-fine generation, for worlds too large to solve in one go and that need structure at a larger scale than
+// any rule can see. A small coarse grid (such as biomes) is generated first, then each coarse cell is expanded into a
+// `scale` x `scale` block of fine cells, with every fine tile's rule depending on the coarse tile it falls within:
+//
+//     let biomes = collapse_rule(initialize(64, 64, Unset), ...)?;
+//     let world = collapse_hierarchical(&biomes, 32, &tiles, |biome, tile| match (biome, tile) {
+//         (Ocean, Water) | (Land, Grass) => land_rule.clone(),
+//         (_, Sand) => CollapseRule::NextTo(Box::new(CollapseRule::Is(Water))),
+//         _ => CollapseRule::False,
+//     }, &[], Unset, Outer, 8, 1, 2)?;
+//
+// The output can be handed straight back in as the coarse grid of another, finer level.
+
+// Expands `coarse` into a grid `scale` times its width and height, solving one block at a time so that no single
+// solve is larger than a block and its margin.
+// | fine_rule
+// + (parent, tile) -> the rule for placing that tile inside that parent. CollapseRule::False keeps the tile out of
+// + the parent entirely.
+// | margin
+// + How many cells around each block are solved along with it, so that a block leaves room for its neighbours.
+// + Only the block itself is kept, the margin is solved again with the block it belongs to. This should be at least
+// + as far as the rules look.
+// Blocks are solved as though everything beyond the window (bar the edge of the whole grid) were unset, so once a block
+// is in place the cells along its edge, on both sides, are checked again on the whole grid. If any fail the block is
+// solved again, up to BACKTRACK_ATTEMPTS times before giving up.
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_hierarchical<C, T>(
+    coarse: &Grid<C>,
+    scale: usize,
+    tile_options: &[T],
+    fine_rule: impl Fn(&C, &T) -> CollapseRule<T>,
+    tile_weights: &[f32],
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    margin: usize,
+) -> Option<Grid<T>>
+where
+    C: PartialEq + Eq + Hash + Clone + Sync + Send + 'static,
+    T: PartialEq + Eq + Hash + Clone + Sync + Send + 'static,
+{
+    let scale = scale.max(1);
+    let fine_width = coarse.get_width() * scale;
+    let fine_height = coarse.get_height() * scale;
+    let mut fine = Grid::new(vec![unset.clone(); fine_width * fine_height], fine_width);
+    let fine_history = Grid::new(vec![unset.clone(); fine_width * fine_height], fine_width);
+    let coarse_shared = Arc::new(coarse.clone());
+
+    for coarse_i in 0..coarse.get_area() {
+        let coarse_pos = coarse.i_to_pos(coarse_i);
+        let block_x = coarse_pos.x as usize * scale;
+        let block_y = coarse_pos.y as usize * scale;
+
+        let x0 = block_x.saturating_sub(margin);
+        let y0 = block_y.saturating_sub(margin);
+        let x1 = (block_x + scale + margin).min(fine_width);
+        let y1 = (block_y + scale + margin).min(fine_height);
+        let offset = Pos::new(x0 as isize, y0 as isize);
+
+        let window = Grid::new(
+            (y0..y1)
+                .flat_map(|y| (x0..x1).map(move |x| (x, y)))
+                .map(|(x, y)| fine.get(&Pos::new(x as isize, y as isize), outer.clone()))
+                .collect(),
+            x1 - x0,
+        );
+        let history = Grid::new(vec![unset.clone(); window.get_area()], window.get_width());
+
+        // Only the parents that fall inside this window need a branch in each rule
+        let mut parents: Vec<C> = vec![];
+        for y in (y0 / scale)..y1.div_ceil(scale) {
+            for x in (x0 / scale)..x1.div_ceil(scale) {
+                let parent = coarse.get_ref(&Pos::new(x as isize, y as isize), &coarse.get_cells()[0]);
+                if !parents.contains(parent) {
+                    parents.push(parent.clone());
+                }
+            }
+        }
+
+        // The window is solved with unset beyond all of its edges, with anything that looks for the outer tile
+        // checking against the edge of the whole grid instead
+        let fine_outer = outside_fine(fine_width, fine_height, offset.clone());
+        let rules = tile_options
+            .iter()
+            .map(|tile| {
+                CollapseRule::Or(
+                    parents
+                        .iter()
+                        .filter_map(|parent| match fine_rule(parent, tile) {
+                            CollapseRule::False => None,
+                            rule => Some(CollapseRule::And(vec![
+                                parent_is(coarse_shared.clone(), scale, offset.clone(), parent.clone()),
+                                window_rule(rule, &fine_outer, &outer),
+                            ])),
+                        })
+                        .collect(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let reach = rules
+            .iter()
+            .flat_map(|rule| rule_footprint(rule, max_depth))
+            .map(|(dx, dy)| dx.unsigned_abs().max(dy.unsigned_abs()))
+            .max()
+            .unwrap_or(0);
+
+        let mut attempts = 0;
+        loop {
+            // The parent check and the branches around each rule, and the bounds check around each read, take up to
+            // four levels on top of the rule itself
+            let solved = collapse_rule(
+                window.clone(),
+                &history,
+                tile_options,
+                |tile| &rules[tile_options.iter().position(|option| option == tile).unwrap()],
+                tile_weights,
+                unset.clone(),
+                unset.clone(),
+                max_depth + 4,
+                seeds,
+                &[],
+            )?;
+
+            for y in block_y..block_y + scale {
+                for x in block_x..block_x + scale {
+                    let pos = Pos::new(x as isize, y as isize);
+                    fine.set(
+                        &pos,
+                        solved.get(&Pos::new(pos.x - offset.x, pos.y - offset.y), outer.clone()),
+                    );
+                }
+            }
+
+            // The seam: cells either side of the block's edge that are close enough to read across it, some of which
+            // were kept from earlier blocks and never saw this one
+            let seam_holds = (block_y.saturating_sub(reach)..(block_y + scale + reach).min(fine_height))
+                .flat_map(|y| {
+                    (block_x.saturating_sub(reach)..(block_x + scale + reach).min(fine_width))
+                        .map(move |x| Pos::new(x as isize, y as isize))
+                })
+                .filter(|pos| {
+                    let inner = |p: isize, block: usize| {
+                        p >= (block + reach) as isize && p < (block + scale) as isize - reach as isize
+                    };
+                    !(inner(pos.x, block_x) && inner(pos.y, block_y))
+                })
+                .all(|pos| {
+                    let tile = fine.get(&pos, outer.clone());
+                    if tile == unset {
+                        return true;
+                    }
+                    let parent = coarse.get_ref(
+                        &Pos::new(pos.x / scale as isize, pos.y / scale as isize),
+                        &coarse.get_cells()[0],
+                    );
+                    check_rule(
+                        &fine,
+                        &fine_history,
+                        &pos,
+                        &fine_rule(parent, &tile),
+                        unset.clone(),
+                        outer.clone(),
+                        max_depth,
+                    )
+                });
+            if seam_holds {
+                break;
+            }
+
+            attempts += 1;
+            if attempts >= BACKTRACK_ATTEMPTS {
+                return None;
+            }
+        }
+    }
+
+    Some(fine)
+}
+
+// Checks the coarse tile that a cell of the window falls within
+fn parent_is<C, T>(coarse: Arc<Grid<C>>, scale: usize, offset: Pos, parent: C) -> CollapseRule<T>
+where
+    C: PartialEq + Eq + Hash + Clone + Sync + Send + 'static,
+    T: PartialEq + Eq + Hash + Clone + Sync + Send,
+{
+    CollapseRule::Custom(
+        Arc::new(move |_, _, pos| {
+            let x = pos.x + offset.x;
+            let y = pos.y + offset.y;
+            if x < 0 || y < 0 {
+                return false;
+            }
+
+            let coarse_pos = Pos::new(x / scale as isize, y / scale as isize);
+            coarse.is_valid(&coarse_pos) && *coarse.get_ref(&coarse_pos, &parent) == parent
+        }),
+        0,
+    )
+}
+
+// Whether a cell of the window falls beyond the edge of the whole grid
+fn outside_fine<T>(fine_width: usize, fine_height: usize, offset: Pos) -> RulePredicate<T>
+where
+    T: PartialEq + Eq + Hash + Clone + Sync + Send,
+{
+    Arc::new(move |_, _, pos| {
+        let x = pos.x + offset.x;
+        let y = pos.y + offset.y;
+        x < 0 || y < 0 || x >= fine_width as isize || y >= fine_height as isize
+    })
+}
+
+// Rewrites a rule written for the whole grid to be solved in a window that reads unset beyond its edges, so that only
+// the edge of the whole grid reads as the outer tile
+fn window_rule<T>(rule: CollapseRule<T>, fine_outer: &RulePredicate<T>, outer: &T) -> CollapseRule<T>
+where
+    T: PartialEq + Eq + Hash + Clone + Sync + Send,
+{
+    let outside = || CollapseRule::Custom(fine_outer.clone(), 0);
+    let inside = || CollapseRule::Not(Box::new(outside()));
+    let sub = |sub_rule: Box<CollapseRule<T>>| Box::new(window_rule(*sub_rule, fine_outer, outer));
+    match rule {
+        CollapseRule::Is(tile) if tile == *outer => outside(),
+        CollapseRule::Is(tile) => CollapseRule::And(vec![inside(), CollapseRule::Is(tile)]),
+        CollapseRule::Unset => CollapseRule::And(vec![inside(), CollapseRule::Unset]),
+        CollapseRule::Outer => outside(),
+        CollapseRule::InBounds => inside(),
+        CollapseRule::And(sub_rules) => CollapseRule::And(
+            sub_rules
+                .into_iter()
+                .map(|sub_rule| window_rule(sub_rule, fine_outer, outer))
+                .collect(),
+        ),
+        CollapseRule::Or(sub_rules) => CollapseRule::Or(
+            sub_rules
+                .into_iter()
+                .map(|sub_rule| window_rule(sub_rule, fine_outer, outer))
+                .collect(),
+        ),
+        CollapseRule::Not(sub_rule) => CollapseRule::Not(sub(sub_rule)),
+        CollapseRule::Near(sub_rule, radius) => CollapseRule::Near(sub(sub_rule), radius),
+        CollapseRule::NextTo(sub_rule) => CollapseRule::NextTo(sub(sub_rule)),
+        CollapseRule::NextTo1(sub_rule) => CollapseRule::NextTo1(sub(sub_rule)),
+        CollapseRule::Left(sub_rule) => CollapseRule::Left(sub(sub_rule)),
+        CollapseRule::Right(sub_rule) => CollapseRule::Right(sub(sub_rule)),
+        CollapseRule::Up(sub_rule) => CollapseRule::Up(sub(sub_rule)),
+        CollapseRule::Down(sub_rule) => CollapseRule::Down(sub(sub_rule)),
+        CollapseRule::UpLeft(sub_rule) => CollapseRule::UpLeft(sub(sub_rule)),
+        CollapseRule::UpRight(sub_rule) => CollapseRule::UpRight(sub(sub_rule)),
+        CollapseRule::DownLeft(sub_rule) => CollapseRule::DownLeft(sub(sub_rule)),
+        CollapseRule::DownRight(sub_rule) => CollapseRule::DownRight(sub(sub_rule)),
+        CollapseRule::Parenthesis(sub_rule) => CollapseRule::Parenthesis(sub(sub_rule)),
+        CollapseRule::InLayer(layer, sub_rule) => CollapseRule::InLayer(layer, sub(sub_rule)),
+        rule => rule,
+    }
+}
@@ -0,0 +1,105 @@
+use super::grid::Grid;
+use image::{DynamicImage, Rgba, RgbaImage};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// Converting between images and grids, with each distinct color becoming its own tile id.
+//
+// Every image is read as 8-bit RGBA whatever its bit depth or channel count, so the same colors always map to the same
+// ids. Fully transparent pixels all count as the same color, whatever RGB they happen to hold.
+
+// The colors of each tile id, in the order they were first seen
+#[derive(Debug, Clone, Default)]
+pub struct Palette {
+    // Ids below this are left free, such as for `unset` and `outer`
+    first_id: usize,
+    colors: Vec<Rgba<u8>>,
+    ids: HashMap<Rgba<u8>, usize>,
+}
+
+impl Palette {
+    pub fn new(first_id: usize) -> Self {
+        Self {
+            first_id,
+            colors: vec![],
+            ids: HashMap::new(),
+        }
+    }
+
+    // The id for this color, giving it the next free id if it hasn't been seen before
+    pub fn id_of(&mut self, color: Rgba<u8>) -> usize {
+        let color = normalize(color);
+        match self.ids.get(&color) {
+            Some(&id) => id,
+            None => {
+                let id = self.first_id + self.colors.len();
+                self.colors.push(color);
+                self.ids.insert(color, id);
+                id
+            }
+        }
+    }
+
+    pub fn get_id(&self, color: Rgba<u8>) -> Option<usize> {
+        self.ids.get(&normalize(color)).copied()
+    }
+
+    pub fn get_color(&self, id: usize) -> Option<Rgba<u8>> {
+        id.checked_sub(self.first_id)
+            .and_then(|index| self.colors.get(index))
+            .copied()
+    }
+
+    // Every id in the palette, for use as the tile options
+    pub fn ids(&self) -> Vec<usize> {
+        (self.first_id..self.first_id + self.colors.len()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+}
+
+impl Grid<usize> {
+    // Reads every pixel into a tile id, starting from `first_id`
+    pub fn from_image(image: &DynamicImage, first_id: usize) -> (Grid<usize>, Palette) {
+        let mut palette = Palette::new(first_id);
+        let grid = Grid::from_image_with_palette(image, &mut palette);
+        (grid, palette)
+    }
+
+    // Same as from_image, but adds to an existing palette so that several images can share the same ids
+    pub fn from_image_with_palette(image: &DynamicImage, palette: &mut Palette) -> Grid<usize> {
+        let rgba = image.to_rgba8();
+        let cells = rgba.pixels().map(|pixel| palette.id_of(*pixel)).collect();
+        Grid::new(cells, rgba.width() as usize)
+    }
+
+    // Writes each tile back out as its palette color, tiles missing from the palette (such as `unset`) come out
+    // transparent
+    pub fn to_image(&self, palette: &Palette) -> RgbaImage {
+        self.to_image_with(|id| palette.get_color(*id).unwrap_or(Rgba([0, 0, 0, 0])))
+    }
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> Grid<T> {
+    pub fn to_image_with(&self, color_of: impl Fn(&T) -> Rgba<u8>) -> RgbaImage {
+        let width = self.get_width() as u32;
+        let height = self.get_height() as u32;
+        RgbaImage::from_fn(width, height, |x, y| {
+            color_of(&self.get_cells()[(y * width + x) as usize])
+        })
+    }
+}
+
+fn normalize(color: Rgba<u8>) -> Rgba<u8> {
+    if color.0[3] == 0 {
+        Rgba([0, 0, 0, 0])
+    } else {
+        color
+    }
+}
@@ -0,0 +1,416 @@
+use super::collapse_rules::{collapse_rule_with_rng, evaluate_rule, CollapseRule, Truth, BACKTRACK_ATTEMPTS};
+use super::grid::Grid;
+use rand::Rng;
+use std::hash::Hash;
+use std::sync::Arc;
+
+// Several grids of the same size stacked on top of one another, such as terrain, objects and decorations. Rules for
+// one layer can look at the others through CollapseRule::InLayer.
+#[derive(Debug, Clone)]
+pub struct LayeredGrid<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    pub layers: Vec<Grid<T>>,
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> LayeredGrid<T> {
+    pub fn new(layers: Vec<Grid<T>>) -> Self {
+        Self { layers }
+    }
+
+    // `count` layers, each filled with `fill`
+    pub fn filled(width: usize, height: usize, count: usize, fill: T) -> Self {
+        Self {
+            layers: (0..count)
+                .map(|_| Grid::new(vec![fill.clone(); width * height], width))
+                .collect(),
+        }
+    }
+
+    pub fn layer(&self, layer: usize) -> &Grid<T> {
+        &self.layers[layer]
+    }
+}
+
+// Solves one layer at a time, starting from layer 0. While a layer is being solved, InLayer reads the other layers as
+// they stood when it started, so rules should generally only look down at layers that have already been solved.
+// Layers that already have every cell set are left as they are.
+// | layer_options
+// + The tile options for each layer
+// | layer_rule
+// + (layer, tile) -> the rule for placing that tile in that layer
+// | rng
+// + Every random choice is drawn from this, so seeding it gives the same layers each time
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_layers<T: PartialEq + Eq + Hash + Clone + Sync + Send + 'static>(
+    mut layered: LayeredGrid<T>,
+    history: &LayeredGrid<T>,
+    layer_options: &[Vec<T>],
+    layer_rule: impl Fn(usize, &T) -> CollapseRule<T>,
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    rng: &mut impl Rng,
+) -> Option<LayeredGrid<T>> {
+    for (layer, options) in layer_options.iter().enumerate().take(layered.layers.len()) {
+        let snapshots = layered.layers.iter().cloned().map(Arc::new).collect::<Vec<_>>();
+        let rules = options
+            .iter()
+            .map(|tile| resolve_layers(layer_rule(layer, tile), layer, &snapshots, &unset, &outer, max_depth))
+            .collect::<Vec<_>>();
+
+        let solved = collapse_rule_with_rng(
+            layered.layers[layer].clone(),
+            &history.layers[layer],
+            options,
+            |tile| &rules[options.iter().position(|option| option == tile).unwrap()],
+            &[],
+            unset.clone(),
+            outer.clone(),
+            max_depth,
+            seeds,
+            &[],
+            BACKTRACK_ATTEMPTS,
+            None,
+            rng,
+            &mut |_, _| {},
+        )?;
+        layered.layers[layer] = solved;
+    }
+
+    Some(layered)
+}
+
+// Solves every layer at once, by treating each cell as holding one tile from every layer. This finds combinations
+// that solving layer by layer can miss (such as a lower layer having to make room for an upper one), but the number
+// of options per cell is every layer's option count multiplied together, so it is best kept to a few small layers.
+// Tiles already set in some layers of a cell (but not all) are pinned, and rules reading that cell see them there.
+// Custom rules are handed their own layer, copied out of the joint grid on every check with the pinned tiles filled in.
+// The rng is used the same way as in collapse_layers.
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_layers_jointly<T: PartialEq + Eq + Hash + Clone + Sync + Send + 'static>(
+    layered: LayeredGrid<T>,
+    history: &LayeredGrid<T>,
+    layer_options: &[Vec<T>],
+    layer_rule: impl Fn(usize, &T) -> CollapseRule<T>,
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    rng: &mut impl Rng,
+) -> Option<LayeredGrid<T>> {
+    let layer_count = layered.layers.len();
+    let joint_unset = vec![unset.clone(); layer_count];
+    let joint_outer = vec![outer.clone(); layer_count];
+
+    // Every combination of one tile per layer
+    let mut combinations: Vec<Vec<T>> = vec![vec![]];
+    for options in layer_options.iter().take(layer_count) {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                options.iter().map(move |tile| {
+                    let mut combination = combination.clone();
+                    combination.push(tile.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+
+    // A cell that has already been set in some layers can only take the combinations that agree with it, which is
+    // enforced by adding the set tiles to every combination's rule
+    let joint = |layered: &LayeredGrid<T>| {
+        let cells = (0..layered.layers[0].get_area())
+            .map(|i| {
+                let stack = layered
+                    .layers
+                    .iter()
+                    .map(|layer| layer.get_cells()[i].clone())
+                    .collect::<Vec<_>>();
+                if stack.iter().all(|tile| *tile != unset) {
+                    stack
+                } else {
+                    joint_unset.clone()
+                }
+            })
+            .collect();
+        Grid::new(cells, layered.layers[0].get_width())
+    };
+    let joint_grid = joint(&layered);
+    let joint_history = joint(history);
+
+    // The cells only set in some layers, which the joint grid leaves unset
+    let partial = (0..layered.layers[0].get_area())
+        .map(|i| {
+            let stack = layered
+                .layers
+                .iter()
+                .map(|layer| layer.get_cells()[i].clone())
+                .collect::<Vec<_>>();
+            match stack.iter().all(|tile| *tile != unset) {
+                true => joint_unset.clone(),
+                false => stack,
+            }
+        })
+        .collect::<Vec<_>>();
+    let pins = match partial.iter().any(|stack| *stack != joint_unset) {
+        true => Some(Arc::new(Grid::new(partial, layered.layers[0].get_width()))),
+        false => None,
+    };
+
+    let pinned = Arc::new(layered.clone());
+    let rules = combinations
+        .iter()
+        .map(|combination| {
+            let mut layer_rules = (0..layer_count)
+                .map(|layer| {
+                    lift_rule(
+                        layer_rule(layer, &combination[layer]),
+                        layer,
+                        Some(combination),
+                        &combinations,
+                        &pins,
+                        &unset,
+                        &outer,
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            let pinned = pinned.clone();
+            let combination_for_pins = combination.clone();
+            let unset_for_pins = unset.clone();
+            layer_rules.push(CollapseRule::Custom(
+                Arc::new(move |_, _, pos| {
+                    pinned
+                        .layers
+                        .iter()
+                        .zip(combination_for_pins.iter())
+                        .all(|(layer, tile)| {
+                            let pinned_tile = layer.get_ref(pos, &unset_for_pins);
+                            *pinned_tile == unset_for_pins || pinned_tile == tile
+                        })
+                }),
+                0,
+            ));
+            CollapseRule::And(layer_rules)
+        })
+        .collect::<Vec<_>>();
+
+    let solved = collapse_rule_with_rng(
+        joint_grid,
+        &joint_history,
+        &combinations,
+        |combination| &rules[combinations.iter().position(|option| option == combination).unwrap()],
+        &[],
+        joint_unset.clone(),
+        joint_outer,
+        max_depth,
+        seeds,
+        &[],
+        BACKTRACK_ATTEMPTS,
+        None,
+        rng,
+        &mut |_, _| {},
+    )?;
+
+    let width = solved.get_width();
+    Some(LayeredGrid::new(
+        (0..layer_count)
+            .map(|layer| {
+                Grid::new(
+                    solved.get_cells().iter().map(|stack| stack[layer].clone()).collect(),
+                    width,
+                )
+            })
+            .collect(),
+    ))
+}
+
+// Replaces each InLayer with a Custom rule that checks the matching snapshot, or with the rule itself where it refers
+// to the layer being solved
+fn resolve_layers<T: PartialEq + Eq + Hash + Clone + Sync + Send + 'static>(
+    rule: CollapseRule<T>,
+    layer: usize,
+    snapshots: &[Arc<Grid<T>>],
+    unset: &T,
+    outer: &T,
+    max_depth: usize,
+) -> CollapseRule<T> {
+    let resolve = |sub_rule: CollapseRule<T>| resolve_layers(sub_rule, layer, snapshots, unset, outer, max_depth);
+    let resolve_box = |sub_rule: Box<CollapseRule<T>>| Box::new(resolve(*sub_rule));
+
+    match rule {
+        CollapseRule::InLayer(other_layer, sub_rule) if other_layer == layer => resolve(*sub_rule),
+        CollapseRule::InLayer(other_layer, sub_rule) => {
+            // Anything nested within is read from the other layer too, so InLayer back to this layer can't be told
+            // apart from the snapshot
+            let other = snapshots[other_layer].clone();
+            let sub_rule = resolve_layers(*sub_rule, other_layer, snapshots, unset, outer, max_depth);
+            let (unset, outer) = (unset.clone(), outer.clone());
+            CollapseRule::Custom(
+                Arc::new(move |_, history_grid, pos| {
+                    evaluate_rule(
+                        &other,
+                        history_grid,
+                        pos,
+                        &sub_rule,
+                        unset.clone(),
+                        outer.clone(),
+                        max_depth,
+                    ) != Truth::False
+                }),
+                0,
+            )
+        }
+        CollapseRule::And(sub_rules) => CollapseRule::And(sub_rules.into_iter().map(resolve).collect()),
+        CollapseRule::Or(sub_rules) => CollapseRule::Or(sub_rules.into_iter().map(resolve).collect()),
+        CollapseRule::Not(sub_rule) => CollapseRule::Not(resolve_box(sub_rule)),
+        CollapseRule::Near(sub_rule, radius) => CollapseRule::Near(resolve_box(sub_rule), radius),
+        CollapseRule::NextTo(sub_rule) => CollapseRule::NextTo(resolve_box(sub_rule)),
+        CollapseRule::NextTo1(sub_rule) => CollapseRule::NextTo1(resolve_box(sub_rule)),
+        CollapseRule::Left(sub_rule) => CollapseRule::Left(resolve_box(sub_rule)),
+        CollapseRule::Right(sub_rule) => CollapseRule::Right(resolve_box(sub_rule)),
+        CollapseRule::Up(sub_rule) => CollapseRule::Up(resolve_box(sub_rule)),
+        CollapseRule::Down(sub_rule) => CollapseRule::Down(resolve_box(sub_rule)),
+        CollapseRule::UpLeft(sub_rule) => CollapseRule::UpLeft(resolve_box(sub_rule)),
+        CollapseRule::UpRight(sub_rule) => CollapseRule::UpRight(resolve_box(sub_rule)),
+        CollapseRule::DownLeft(sub_rule) => CollapseRule::DownLeft(resolve_box(sub_rule)),
+        CollapseRule::DownRight(sub_rule) => CollapseRule::DownRight(resolve_box(sub_rule)),
+        CollapseRule::Parenthesis(sub_rule) => CollapseRule::Parenthesis(resolve_box(sub_rule)),
+        rule => rule,
+    }
+}
+
+// Turns a rule for a single layer into one over every layer at once, where each cell holds one tile per layer.
+// `placing` is the combination being placed while the rule is still looking at its own cell, since that cell is unset
+// when the rule is checked. `pins` holds the tiles of cells only set in some layers, which read as set in those layers
+// while the cell itself is still unset.
+fn lift_rule<T: PartialEq + Eq + Hash + Clone + Sync + Send + 'static>(
+    rule: CollapseRule<T>,
+    layer: usize,
+    placing: Option<&[T]>,
+    combinations: &[Vec<T>],
+    pins: &Option<Arc<Grid<Vec<T>>>>,
+    unset: &T,
+    outer: &T,
+) -> CollapseRule<Vec<T>> {
+    let lift = |sub_rule: CollapseRule<T>| lift_rule(sub_rule, layer, placing, combinations, pins, unset, outer);
+    let lift_box = |sub_rule: Box<CollapseRule<T>>| Box::new(lift(*sub_rule));
+    let lift_moved =
+        |sub_rule: Box<CollapseRule<T>>| Box::new(lift_rule(*sub_rule, layer, None, combinations, pins, unset, outer));
+    let layer_count = combinations.first().map_or(0, Vec::len);
+    let no_pins = vec![unset.clone(); layer_count];
+
+    match rule {
+        // Listing out every combination that has this tile in this layer keeps unset cells reading as unknown
+        CollapseRule::Is(tile) if placing.is_some() => {
+            if placing.is_some_and(|combination| combination[layer] == tile) {
+                CollapseRule::True
+            } else {
+                CollapseRule::False
+            }
+        }
+        CollapseRule::Is(tile) if tile == *outer => CollapseRule::Is(vec![outer.clone(); layer_count]),
+        CollapseRule::Is(tile) => {
+            let listed = CollapseRule::Or(
+                combinations
+                    .iter()
+                    .filter(|combination| combination[layer] == tile)
+                    .map(|combination| CollapseRule::Is(combination.clone()))
+                    .collect(),
+            );
+            let Some(pins) = pins else {
+                return listed;
+            };
+
+            // Whether the cell has a tile pinned in this layer that is (or isn't) this one
+            let pinned_as = |matching: bool| {
+                let (pins, tile, unset, no_pins) = (pins.clone(), tile.clone(), unset.clone(), no_pins.clone());
+                CollapseRule::Custom(
+                    Arc::new(move |_, _, pos| {
+                        let pinned_tile = &pins.get_ref(pos, &no_pins)[layer];
+                        *pinned_tile != unset && (*pinned_tile == tile) == matching
+                    }),
+                    0,
+                )
+            };
+            CollapseRule::And(vec![
+                CollapseRule::Not(Box::new(pinned_as(false))),
+                CollapseRule::Or(vec![listed, pinned_as(true)]),
+            ])
+        }
+        // The history may hold combinations that aren't options, so it is read directly. A cell is only treated as
+        // unset if every layer is, matching how the history is built.
+        CollapseRule::Was(tile) => {
+            let (unset, outer) = (unset.clone(), vec![outer.clone(); layer_count]);
+            CollapseRule::Custom(
+                Arc::new(move |_, history_grid, pos| {
+                    let stack = history_grid.get_ref(pos, &outer);
+                    stack[layer] == tile || stack.iter().all(|history_tile| *history_tile == unset)
+                }),
+                0,
+            )
+        }
+        CollapseRule::Custom(predicate, radius) => {
+            // The predicate is handed its own layer of whichever grids it is checked against, copied out afresh every
+            // time
+            let (unset, outer, pins) = (unset.clone(), outer.clone(), pins.clone());
+            CollapseRule::Custom(
+                Arc::new(move |grid, history_grid, pos| {
+                    predicate(
+                        &layer_view(grid, layer, pins.as_deref(), &unset, &outer),
+                        &layer_view(history_grid, layer, None, &unset, &unset),
+                        pos,
+                    )
+                }),
+                radius,
+            )
+        }
+        CollapseRule::InLayer(other_layer, sub_rule) => {
+            lift_rule(*sub_rule, other_layer, placing, combinations, pins, unset, outer)
+        }
+        CollapseRule::And(sub_rules) => CollapseRule::And(sub_rules.into_iter().map(lift).collect()),
+        CollapseRule::Or(sub_rules) => CollapseRule::Or(sub_rules.into_iter().map(lift).collect()),
+        CollapseRule::Not(sub_rule) => CollapseRule::Not(lift_box(sub_rule)),
+        CollapseRule::Near(sub_rule, radius) => CollapseRule::Near(lift_moved(sub_rule), radius),
+        CollapseRule::NextTo(sub_rule) => CollapseRule::NextTo(lift_moved(sub_rule)),
+        CollapseRule::NextTo1(sub_rule) => CollapseRule::NextTo1(lift_moved(sub_rule)),
+        CollapseRule::Left(sub_rule) => CollapseRule::Left(lift_moved(sub_rule)),
+        CollapseRule::Right(sub_rule) => CollapseRule::Right(lift_moved(sub_rule)),
+        CollapseRule::Up(sub_rule) => CollapseRule::Up(lift_moved(sub_rule)),
+        CollapseRule::Down(sub_rule) => CollapseRule::Down(lift_moved(sub_rule)),
+        CollapseRule::UpLeft(sub_rule) => CollapseRule::UpLeft(lift_moved(sub_rule)),
+        CollapseRule::UpRight(sub_rule) => CollapseRule::UpRight(lift_moved(sub_rule)),
+        CollapseRule::DownLeft(sub_rule) => CollapseRule::DownLeft(lift_moved(sub_rule)),
+        CollapseRule::DownRight(sub_rule) => CollapseRule::DownRight(lift_moved(sub_rule)),
+        CollapseRule::Parenthesis(sub_rule) => CollapseRule::Parenthesis(lift_box(sub_rule)),
+        CollapseRule::True => CollapseRule::True,
+        CollapseRule::False => CollapseRule::False,
+        CollapseRule::InBounds => CollapseRule::InBounds,
+        CollapseRule::Unset => CollapseRule::Unset,
+        CollapseRule::Outer => CollapseRule::Outer,
+    }
+}
+
+// One layer of a joint grid, with the tiles in `pins` filled in where the joint grid is unset
+fn layer_view<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<Vec<T>>,
+    layer: usize,
+    pins: Option<&Grid<Vec<T>>>,
+    unset: &T,
+    fallback: &T,
+) -> Grid<T> {
+    Grid::new(
+        grid.get_cells()
+            .iter()
+            .enumerate()
+            .map(|(i, stack)| match pins {
+                Some(pins) if stack.iter().all(|tile| tile == unset) => {
+                    pins.get_cells().get(i).map_or(unset, |pinned| &pinned[layer]).clone()
+                }
+                _ => stack.get(layer).unwrap_or(fallback).clone(),
+            })
+            .collect(),
+        grid.get_width(),
+    )
+}
@@ -0,0 +1,123 @@
+use super::collapse_rules::CollapseRule;
+use super::grid::Grid;
+use super::pos::Direction;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// A ruleset learned from a sample grid, laid out so that it can be handed straight to collapse_rule:
+//
+//     collapse_rule(grid, &history_grid, &learned.tiles, |tile| &learned.rules[tile], &learned.weights, ...)
+pub struct LearnedRules<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    // Every distinct tile in the sample, in the order they were first seen
+    pub tiles: Vec<T>,
+    // Each tile's adjacency rule
+    pub rules: HashMap<T, CollapseRule<T>>,
+    // How often each tile (in the same order as `tiles`) appeared in the sample, as a fraction of its area
+    pub weights: Vec<f32>,
+    // Every tile seen next to each tile in each direction, along with how many times it was seen there
+    pub neighbours: HashMap<(T, Direction), Vec<(T, usize)>>,
+}
+
+// Scans the sample and builds a rule for each tile that only allows it to sit next to tiles it was seen next to, in the
+// direction it was seen next to them. Unset cells in the sample are skipped, so partially painted samples can be used.
+// | outer
+// + When given, cells beyond the sample's edges are learned as `outer` neighbours, so that tiles only end up along the
+// + edges they were seen along in the sample.
+// + When None, any tile may sit along the edge of the grid.
+pub fn learn_rules<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    sample: &Grid<T>,
+    unset: T,
+    outer: Option<T>,
+) -> LearnedRules<T> {
+    let mut tiles = vec![];
+    let mut tile_counts = HashMap::<T, usize>::new();
+    let mut neighbour_counts = HashMap::<(T, Direction), HashMap<T, usize>>::new();
+
+    for i in 0..sample.get_area() {
+        let tile = &sample.get_cells()[i];
+        if *tile == unset {
+            continue;
+        }
+        if !tile_counts.contains_key(tile) {
+            tiles.push(tile.clone());
+        }
+        *tile_counts.entry(tile.clone()).or_insert(0) += 1;
+
+        let pos = sample.i_to_pos(i);
+        for direction in Direction::ALL {
+            let (dx, dy) = direction.offset();
+            let neighbour_pos = pos.rel(dx, dy);
+            let neighbour = match (sample.is_valid(&neighbour_pos), &outer) {
+                (true, _) => sample.get(&neighbour_pos, unset.clone()),
+                (false, Some(outer)) => outer.clone(),
+                (false, None) => continue,
+            };
+            if neighbour == unset {
+                continue;
+            }
+            *neighbour_counts
+                .entry((tile.clone(), direction))
+                .or_default()
+                .entry(neighbour)
+                .or_insert(0) += 1;
+        }
+    }
+
+    // Keep the neighbours in a stable order so the same sample always produces the same rules
+    let neighbours = neighbour_counts
+        .into_iter()
+        .map(|(key, counts)| {
+            let mut counts = counts.into_iter().collect::<Vec<_>>();
+            counts.sort_by_key(|(neighbour, _)| tiles.iter().position(|tile| tile == neighbour));
+            (key, counts)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let rules = tiles
+        .iter()
+        .map(|tile| {
+            let direction_rules = Direction::ALL
+                .iter()
+                .map(|direction| {
+                    let mut allowed = neighbours
+                        .get(&(tile.clone(), *direction))
+                        .map(|seen| {
+                            seen.iter()
+                                .map(|(neighbour, _)| CollapseRule::Is(neighbour.clone()))
+                                .collect::<Vec<_>>()
+                        })
+                        .unwrap_or_default();
+                    if outer.is_none() {
+                        allowed.push(CollapseRule::Outer);
+                    }
+                    in_direction(*direction, CollapseRule::Or(allowed))
+                })
+                .collect();
+            (tile.clone(), CollapseRule::And(direction_rules))
+        })
+        .collect();
+
+    let weights = tiles
+        .iter()
+        .map(|tile| tile_counts[tile] as f32 / sample.get_area() as f32)
+        .collect();
+
+    LearnedRules {
+        tiles,
+        rules,
+        weights,
+        neighbours,
+    }
+}
+
+fn in_direction<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    direction: Direction,
+    rule: CollapseRule<T>,
+) -> CollapseRule<T> {
+    match direction {
+        Direction::Left => CollapseRule::Left(Box::new(rule)),
+        Direction::Right => CollapseRule::Right(Box::new(rule)),
+        Direction::Up => CollapseRule::Up(Box::new(rule)),
+        Direction::Down => CollapseRule::Down(Box::new(rule)),
+    }
+}
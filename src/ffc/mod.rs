@@ -1,4 +1,25 @@
+pub mod analysis;
+pub mod batch;
 pub mod collapse;
 pub mod collapse_rules;
+pub mod compiled_rules;
+pub mod constraints;
+pub mod explain;
 pub mod grid;
+pub mod hierarchy;
+#[cfg(feature = "image")]
+pub mod image_io;
+pub mod layers;
+pub mod learn;
 pub mod pos;
+pub mod recorder;
+pub mod repair;
+pub mod restart;
+pub mod sequence;
+pub mod text;
+pub mod tiled;
+#[cfg(feature = "tmx")]
+pub mod tmx;
+pub mod validate;
+#[cfg(feature = "xml")]
+pub mod xml;
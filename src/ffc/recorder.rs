@@ -0,0 +1,205 @@
+use super::collapse_rules::SolverEvent;
+use super::grid::Grid;
+use std::collections::HashSet;
+use std::hash::Hash;
+
+// Captures snapshots of the grid as collapse_rule_observed works on it, which can then be written out as an animation
+// (with the `image` feature) to see how a ruleset fills in and where it backtracks:
+//
+//     let mut recorder = Recorder::new(10);
+//     collapse_rule_observed(..., &mut |grid, event| recorder.observe(grid, event));
+//     recorder.write_gif("solve.gif", |tile| palette[tile], Rgba([255, 0, 0, 255]), 4, 50)?;
+
+#[derive(Debug, Clone)]
+pub struct RecordedFrame<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    pub grid: Grid<T>,
+    // Cells that were cleared by backtracking since the previous frame and haven't been set again yet
+    pub backtracked: Vec<usize>,
+}
+
+pub struct Recorder<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    // The number of solver steps between each frame
+    interval: usize,
+    steps_since_frame: usize,
+    backtracked: HashSet<usize>,
+    pub frames: Vec<RecordedFrame<T>>,
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> Recorder<T> {
+    pub fn new(interval: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            steps_since_frame: 0,
+            backtracked: HashSet::new(),
+            frames: vec![],
+        }
+    }
+
+    pub fn observe(&mut self, grid: &Grid<T>, event: &SolverEvent<T>) {
+        match event {
+            SolverEvent::Placed { pos, .. } => {
+                self.backtracked.remove(&grid.pos_to_i(pos));
+            }
+            SolverEvent::Removed { pos, .. } => {
+                self.backtracked.insert(grid.pos_to_i(pos));
+            }
+            // Always finish on the final grid, whether or not it lines up with the interval
+            SolverEvent::Finished | SolverEvent::Failed => {
+                self.capture(grid);
+                return;
+            }
+        }
+
+        self.steps_since_frame += 1;
+        if self.steps_since_frame >= self.interval {
+            self.capture(grid);
+        }
+    }
+
+    fn capture(&mut self, grid: &Grid<T>) {
+        let mut backtracked = self.backtracked.drain().collect::<Vec<_>>();
+        backtracked.sort();
+        self.frames.push(RecordedFrame {
+            grid: grid.clone(),
+            backtracked,
+        });
+        self.steps_since_frame = 0;
+    }
+}
+
+#[cfg(feature = "image")]
+pub use self::animation::RecordingError;
+
+#[cfg(feature = "image")]
+mod animation {
+    use super::{RecordedFrame, Recorder};
+    use image::codecs::gif::{GifEncoder, Repeat};
+    use image::{Delay, Frame, Rgba, RgbaImage};
+    use std::fmt;
+    use std::fs::File;
+    use std::hash::Hash;
+    use std::io::BufWriter;
+    use std::path::Path;
+
+    #[derive(Debug)]
+    pub enum RecordingError {
+        Io(std::io::Error),
+        Image(image::ImageError),
+        Png(png::EncodingError),
+        NoFrames,
+    }
+
+    impl fmt::Display for RecordingError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                RecordingError::Io(error) => write!(f, "Failed to write recording: {error}"),
+                RecordingError::Image(error) => write!(f, "Failed to encode GIF: {error}"),
+                RecordingError::Png(error) => write!(f, "Failed to encode APNG: {error}"),
+                RecordingError::NoFrames => write!(f, "Nothing has been recorded"),
+            }
+        }
+    }
+
+    impl std::error::Error for RecordingError {}
+
+    impl From<std::io::Error> for RecordingError {
+        fn from(error: std::io::Error) -> Self {
+            RecordingError::Io(error)
+        }
+    }
+
+    impl From<image::ImageError> for RecordingError {
+        fn from(error: image::ImageError) -> Self {
+            RecordingError::Image(error)
+        }
+    }
+
+    impl From<png::EncodingError> for RecordingError {
+        fn from(error: png::EncodingError) -> Self {
+            RecordingError::Png(error)
+        }
+    }
+
+    impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> Recorder<T> {
+        // | backtrack_color
+        // + Drawn over cells that were cleared by backtracking since the previous frame
+        // | scale
+        // + The width and height in pixels of each cell
+        pub fn render_frames(
+            &self,
+            color_of: impl Fn(&T) -> Rgba<u8>,
+            backtrack_color: Rgba<u8>,
+            scale: u32,
+        ) -> Vec<RgbaImage> {
+            self.frames
+                .iter()
+                .map(|frame| render_frame(frame, &color_of, backtrack_color, scale.max(1)))
+                .collect()
+        }
+
+        pub fn write_gif(
+            &self,
+            path: impl AsRef<Path>,
+            color_of: impl Fn(&T) -> Rgba<u8>,
+            backtrack_color: Rgba<u8>,
+            scale: u32,
+            frame_delay_ms: u32,
+        ) -> Result<(), RecordingError> {
+            if self.frames.is_empty() {
+                return Err(RecordingError::NoFrames);
+            }
+
+            let mut encoder = GifEncoder::new(BufWriter::new(File::create(path)?));
+            encoder.set_repeat(Repeat::Infinite)?;
+            encoder.encode_frames(
+                self.render_frames(color_of, backtrack_color, scale)
+                    .into_iter()
+                    .map(|image| Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(frame_delay_ms, 1))),
+            )?;
+            Ok(())
+        }
+
+        pub fn write_apng(
+            &self,
+            path: impl AsRef<Path>,
+            color_of: impl Fn(&T) -> Rgba<u8>,
+            backtrack_color: Rgba<u8>,
+            scale: u32,
+            frame_delay_ms: u16,
+        ) -> Result<(), RecordingError> {
+            let images = self.render_frames(color_of, backtrack_color, scale);
+            let first = images.first().ok_or(RecordingError::NoFrames)?;
+
+            let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), first.width(), first.height());
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(images.len() as u32, 0)?;
+            encoder.set_frame_delay(frame_delay_ms, 1000)?;
+
+            let mut writer = encoder.write_header()?;
+            for image in images.iter() {
+                writer.write_image_data(image.as_raw())?;
+            }
+            writer.finish()?;
+            Ok(())
+        }
+    }
+
+    fn render_frame<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+        frame: &RecordedFrame<T>,
+        color_of: &impl Fn(&T) -> Rgba<u8>,
+        backtrack_color: Rgba<u8>,
+        scale: u32,
+    ) -> RgbaImage {
+        let width = frame.grid.get_width() as u32;
+        let height = frame.grid.get_height() as u32;
+        RgbaImage::from_fn(width * scale, height * scale, |x, y| {
+            let i = ((y / scale) * width + x / scale) as usize;
+            if frame.backtracked.binary_search(&i).is_ok() {
+                backtrack_color
+            } else {
+                color_of(&frame.grid.get_cells()[i])
+            }
+        })
+    }
+}
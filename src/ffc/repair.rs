@@ -0,0 +1,206 @@
+use super::collapse_rules::{rule_footprint, CollapseRule, Truth};
+use super::compiled_rules::{compile_rule, evaluate_compiled_rule, CompiledRule};
+use super::grid::Grid;
+use super::pos::Pos;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::hash::Hash;
+
+// Min-conflicts local search, for grids too large to solve exactly where output that is almost valid will do. Rather
+// than building the grid up a cell at a time, this starts from a complete grid (such as one filled at random, or one
+// that collapse_rule only partly finished) and keeps reassigning cells that break their rules to whichever tile breaks
+// the fewest rules around it.
+
+#[derive(Debug, Clone)]
+pub struct RepairReport {
+    pub iterations: usize,
+    // The cells still breaking their rules (or still unset) when the repair stopped
+    pub violations: Vec<Pos>,
+}
+
+impl RepairReport {
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+// Fills every unset cell with one of the options picked at random from `rng`, as a starting point for repair_rule
+pub fn fill_random<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    mut grid: Grid<T>,
+    tile_options: &[T],
+    unset: T,
+    rng: &mut impl Rng,
+) -> Grid<T> {
+    for i in 0..grid.get_area() {
+        let pos = grid.i_to_pos(i);
+        if grid.get_ref(&pos, &unset) == &unset {
+            if let Some(tile) = tile_options.choose(rng) {
+                grid.set(&pos, tile.clone());
+            }
+        }
+    }
+    grid
+}
+
+// Repairs `grid` for up to `max_iterations` reassignments, returning the repaired grid along with what is still wrong
+// with it. Unset cells count as breaking their rules, so they are filled in along the way. A cell's rule is checked
+// the same way validate_rules checks it, on the grid as it is, and has to come out true.
+// | noise
+// + The chance of reassigning a cell to any option at random instead of the best one, which helps to get out of
+// + spots where every single change makes things worse
+// | pinned
+// + One entry per cell, true for cells that must keep their tile (such as ones placed by hand). Pinned cells are
+// + never reassigned, but still count towards the rules of the cells around them and are still reported if they break
+// + their own.
+// | rng
+// + Every random choice is drawn from this, so seeding it gives the same repair each time
+#[allow(clippy::too_many_arguments)]
+pub fn repair_rule<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    mut grid: Grid<T>,
+    history_grid: &Grid<T>,
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    max_iterations: usize,
+    noise: f32,
+    pinned: Option<&[bool]>,
+    rng: &mut impl Rng,
+) -> (Grid<T>, RepairReport) {
+    let is_pinned = |i: usize| pinned.is_some_and(|pinned| pinned.get(i) == Some(&true));
+
+    let compiled_rules = tile_options
+        .iter()
+        .map(|tile_option| compile_rule(tile_to_rule(tile_option), max_depth))
+        .collect::<Vec<_>>();
+
+    let mut footprint = tile_options
+        .iter()
+        .flat_map(|tile_option| rule_footprint(tile_to_rule(tile_option), max_depth))
+        .collect::<Vec<_>>();
+    footprint.sort();
+    footprint.dedup();
+    let dependents = footprint
+        .iter()
+        .map(|(dx, dy)| (-dx, -dy))
+        .filter(|offset| *offset != (0, 0))
+        .collect::<Vec<_>>();
+
+    macro_rules! violates {
+        ($i: expr) => {
+            violates(
+                &grid,
+                history_grid,
+                $i,
+                tile_options,
+                &compiled_rules,
+                &unset,
+                &outer,
+            )
+        };
+    }
+
+    // Unpinned cells breaking their rules, kept alongside each cell's index into the list so they can be removed
+    // quickly
+    let mut violated: Vec<usize> = vec![];
+    let mut violated_at: Vec<Option<usize>> = vec![None; grid.get_area()];
+
+    macro_rules! mark {
+        ($i: expr, $is_violated: expr) => {
+            let i = $i;
+            match (violated_at[i], $is_violated) {
+                (None, true) => {
+                    violated_at[i] = Some(violated.len());
+                    violated.push(i);
+                }
+                (Some(at), false) => {
+                    violated.swap_remove(at);
+                    if let Some(&moved) = violated.get(at) {
+                        violated_at[moved] = Some(at);
+                    }
+                    violated_at[i] = None;
+                }
+                _ => {}
+            }
+        };
+    }
+
+    for i in (0..grid.get_area()).filter(|&i| !is_pinned(i)) {
+        mark!(i, violates!(i));
+    }
+
+    let mut iterations = 0;
+
+    while iterations < max_iterations && !violated.is_empty() {
+        iterations += 1;
+
+        let i = violated[rng.gen_range(0..violated.len())];
+        let pos = grid.i_to_pos(i);
+        let neighbours = dependents
+            .iter()
+            .map(|(dx, dy)| pos.rel(*dx, *dy))
+            .filter(|neighbour_pos| grid.is_valid(neighbour_pos))
+            .map(|neighbour_pos| grid.pos_to_i(&neighbour_pos))
+            .collect::<Vec<_>>();
+
+        let chosen = if rng.gen::<f32>() < noise {
+            rng.gen_range(0..tile_options.len())
+        } else {
+            // Count how many rules each option would leave broken, between this cell and every cell reading it
+            let conflicts = (0..tile_options.len())
+                .map(|option_i| {
+                    grid.set(&pos, tile_options[option_i].clone());
+                    let own = violates!(i);
+                    let others = neighbours.iter().filter(|&&neighbour_i| violates!(neighbour_i)).count();
+                    own as usize + others
+                })
+                .collect::<Vec<_>>();
+
+            let fewest = conflicts.iter().min().copied().unwrap_or(0);
+            let best = (0..tile_options.len())
+                .filter(|&option_i| conflicts[option_i] == fewest)
+                .collect::<Vec<_>>();
+            match best.choose(rng) {
+                Some(&option_i) => option_i,
+                None => break,
+            }
+        };
+
+        grid.set(&pos, tile_options[chosen].clone());
+        mark!(i, violates!(i));
+        for neighbour_i in neighbours.into_iter().filter(|&neighbour_i| !is_pinned(neighbour_i)) {
+            mark!(neighbour_i, violates!(neighbour_i));
+        }
+    }
+
+    for i in (0..grid.get_area()).filter(|&i| is_pinned(i)) {
+        if violates!(i) {
+            violated.push(i);
+        }
+    }
+    violated.sort();
+    let violations = violated.iter().map(|&i| grid.i_to_pos(i)).collect();
+    (grid, RepairReport { iterations, violations })
+}
+
+// Whether the cell at `i` is unset or breaks the rule for its tile, which unlike in collapse_rule means anything short
+// of true, as the grid is meant to be finished
+fn violates<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    history_grid: &Grid<T>,
+    i: usize,
+    tile_options: &[T],
+    compiled_rules: &[CompiledRule<T>],
+    unset: &T,
+    outer: &T,
+) -> bool {
+    let pos = grid.i_to_pos(i);
+    let tile = grid.get_ref(&pos, outer);
+    let Some(option_i) = tile_options.iter().position(|option| option == tile) else {
+        // Unset, or a tile that isn't one of the options at all
+        return true;
+    };
+
+    evaluate_compiled_rule(grid, history_grid, &pos, &compiled_rules[option_i], unset, outer) != Truth::True
+}
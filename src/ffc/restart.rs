@@ -0,0 +1,164 @@
+use super::collapse::collapse_with_rng;
+use super::collapse_rules::{collapse_rule_with_rng, CollapseRule, BACKTRACK_ATTEMPTS};
+use super::constraints::GlobalConstraint;
+use super::grid::Grid;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::hash::Hash;
+
+// Running a solver again from scratch when it gets stuck, rather than leaving it to grind through backtracking. Each
+// run is given a number of attempts (how many dead ends it can backtrack out of in total before it is abandoned),
+// which the policy can grow from one run to the next, and its own seed worked out from a base seed, so the same base
+// seed always gives the same runs and the same output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    // The same number of attempts on every run
+    Fixed { attempts: usize },
+    // `unit` times the Luby sequence (1, 1, 2, 1, 1, 2, 4, 1, ...), which mixes plenty of short runs in with the
+    // occasional long one
+    Luby { unit: usize },
+    // Starts from `initial` and grows by `factor` each run
+    Geometric { initial: usize, factor: f32 },
+}
+
+impl RestartPolicy {
+    // The attempts for a run, counting from 0
+    pub fn attempts(&self, run: usize) -> usize {
+        match self {
+            RestartPolicy::Fixed { attempts } => *attempts,
+            RestartPolicy::Luby { unit } => unit * luby(run + 1),
+            RestartPolicy::Geometric { initial, factor } => {
+                (*initial as f32 * factor.powi(run as i32)).round() as usize
+            }
+        }
+    }
+}
+
+// The i'th term (counting from 1) of the Luby sequence
+pub fn luby(i: usize) -> usize {
+    let mut i = i.max(1);
+    loop {
+        // Find the smallest k such that i <= 2^k - 1
+        let mut k = 1;
+        while (1 << k) - 1 < i {
+            k += 1;
+        }
+        if i == (1 << k) - 1 {
+            return 1 << (k - 1);
+        }
+        i -= (1 << (k - 1)) - 1;
+    }
+}
+
+// The seed of a run, spread out from the base seed so that neighbouring runs don't get similar seeds
+pub fn run_seed(base_seed: u64, run: usize) -> u64 {
+    base_seed ^ (run as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RestartStats {
+    // Every run made, including the one that succeeded
+    pub runs: usize,
+    // The seed and attempts (dead ends allowed) of each run, in order
+    pub seeds: Vec<u64>,
+    pub attempts: Vec<usize>,
+}
+
+impl RestartStats {
+    // How many times the solver had to start again
+    pub fn restarts(&self) -> usize {
+        self.runs.saturating_sub(1)
+    }
+}
+
+// Runs `solve` with a freshly seeded rng and the policy's attempts until it succeeds, or `max_runs` runs have failed
+pub fn run_with_restarts<R>(
+    policy: RestartPolicy,
+    max_runs: usize,
+    base_seed: u64,
+    mut solve: impl FnMut(&mut StdRng, usize) -> Option<R>,
+) -> (Option<R>, RestartStats) {
+    let mut stats = RestartStats::default();
+    for run in 0..max_runs {
+        let seed = run_seed(base_seed, run);
+        let attempts = policy.attempts(run).max(1);
+        stats.runs += 1;
+        stats.seeds.push(seed);
+        stats.attempts.push(attempts);
+
+        if let Some(result) = solve(&mut StdRng::seed_from_u64(seed), attempts) {
+            return (Some(result), stats);
+        }
+    }
+    (None, stats)
+}
+
+// collapse_rule, restarted according to `policy`. Each run is abandoned once it has backtracked as many times as the
+// policy's attempts for it.
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_rule_restarting<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    grid: Grid<T>,
+    history_grid: &Grid<T>,
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
+    tile_weights: &[f32],
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    constraints: &[GlobalConstraint<T>],
+    policy: RestartPolicy,
+    max_runs: usize,
+    base_seed: u64,
+) -> (Option<Grid<T>>, RestartStats) {
+    run_with_restarts(policy, max_runs, base_seed, |rng, attempts| {
+        collapse_rule_with_rng(
+            grid.clone(),
+            history_grid,
+            tile_options,
+            &tile_to_rule,
+            tile_weights,
+            unset.clone(),
+            outer.clone(),
+            max_depth,
+            seeds,
+            constraints,
+            BACKTRACK_ATTEMPTS,
+            Some(attempts),
+            rng,
+            &mut |_, _| {},
+        )
+    })
+}
+
+// collapse, restarted according to `policy`. Each run is abandoned once it has been left with nothing to place as
+// many times as the policy's attempts for it.
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_restarting<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: Grid<T>,
+    evaluate_order: &[usize],
+    pattern: &Grid<T>,
+    radius: isize,
+    reroll_attempts: usize,
+    climb_amount_on_reroll: usize,
+    unset: T,
+    outer: T,
+    policy: RestartPolicy,
+    max_runs: usize,
+    base_seed: u64,
+) -> (Option<Grid<T>>, RestartStats) {
+    run_with_restarts(policy, max_runs, base_seed, |rng, attempts| {
+        collapse_with_rng(
+            grid.clone(),
+            evaluate_order,
+            pattern,
+            radius,
+            reroll_attempts,
+            Some(attempts),
+            climb_amount_on_reroll,
+            unset.clone(),
+            outer.clone(),
+            rng,
+        )
+    })
+}
@@ -0,0 +1,98 @@
+use super::collapse_rules::{collapse_rule_with_rng, CollapseRule, BACKTRACK_ATTEMPTS};
+use super::constraints::GlobalConstraint;
+use super::grid::Grid;
+use rand::seq::index::sample;
+use rand::Rng;
+use std::hash::Hash;
+
+// Generating a sequence of frames where each one is solved with the frame before it as the history grid, so that
+// CollapseRule::Was can carry things over between them. This can evolve a map over in-game seasons, or animate it.
+//
+//     // Grass stays grass unless it is next to fire, fire always burns out
+//     let frames = collapse_sequence(start, 10, &tiles, |tile| &rules[tile], &[], Unset, Outer, 8, 1, &[], Some(20), &mut rng)?;
+
+// Solves the frame after `previous`. With a limit on the changes, only that many cells picked at random are solved
+// again and every other cell is carried over as it was. Without a limit every cell is solved again, and it is up to
+// the Was rules how much stays the same. Every random choice is drawn from `rng`, so seeding it gives the same frame
+// each time.
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_next_frame<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    previous: &Grid<T>,
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
+    tile_weights: &[f32],
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    constraints: &[GlobalConstraint<T>],
+    max_changes: Option<usize>,
+    rng: &mut impl Rng,
+) -> Option<Grid<T>> {
+    let grid = match max_changes {
+        Some(max_changes) if max_changes < previous.get_area() => {
+            let mut grid = previous.clone();
+            for i in sample(rng, previous.get_area(), max_changes) {
+                grid.set(&previous.i_to_pos(i), unset.clone());
+            }
+            grid
+        }
+        _ => Grid::new(vec![unset.clone(); previous.get_area()], previous.get_width()),
+    };
+
+    collapse_rule_with_rng(
+        grid,
+        previous,
+        tile_options,
+        tile_to_rule,
+        tile_weights,
+        unset,
+        outer,
+        max_depth,
+        seeds,
+        constraints,
+        BACKTRACK_ATTEMPTS,
+        None,
+        rng,
+        &mut |_, _| {},
+    )
+}
+
+// Solves `frames` more frames one after another, returning them all with `first` at the start. Fails if any frame
+// can't be solved.
+// | max_changes
+// + The most cells that can change from one frame to the next, None to solve every cell again each frame
+#[allow(clippy::too_many_arguments)]
+pub fn collapse_sequence<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    first: Grid<T>,
+    frames: usize,
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
+    tile_weights: &[f32],
+    unset: T,
+    outer: T,
+    max_depth: usize,
+    seeds: usize,
+    constraints: &[GlobalConstraint<T>],
+    max_changes: Option<usize>,
+    rng: &mut impl Rng,
+) -> Option<Vec<Grid<T>>> {
+    let mut sequence = vec![first];
+    for _ in 0..frames {
+        let next = collapse_next_frame(
+            sequence.last().expect("Sequence always starts with the first frame"),
+            tile_options,
+            &tile_to_rule,
+            tile_weights,
+            unset.clone(),
+            outer.clone(),
+            max_depth,
+            seeds,
+            constraints,
+            max_changes,
+            rng,
+        )?;
+        sequence.push(next);
+    }
+    Some(sequence)
+}
@@ -0,0 +1,137 @@
+use super::grid::Grid;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+// Converting between grids and text, with each tile drawn as a single character. Row 0 is the first line.
+//
+//     let char_map = CharMap::new([('~', Water), ('.', Sand), ('#', Grass)]);
+//     let sample = Grid::from_text(
+//         "
+//         ~~..
+//         ~.##
+//         ",
+//         &char_map,
+//     )?;
+//
+// Each line is trimmed and blank lines are skipped, so grids can be indented inline along with the surrounding code.
+// This does mean whitespace can't be used for a tile.
+
+#[derive(Debug, Clone)]
+pub struct CharMap<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    to_char: HashMap<T, char>,
+    to_tile: HashMap<char, T>,
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> CharMap<T> {
+    pub fn new(pairs: impl IntoIterator<Item = (char, T)>) -> Self {
+        let mut char_map = CharMap {
+            to_char: HashMap::new(),
+            to_tile: HashMap::new(),
+        };
+        for (c, tile) in pairs {
+            char_map.insert(c, tile);
+        }
+        char_map
+    }
+
+    pub fn insert(&mut self, c: char, tile: T) {
+        self.to_char.insert(tile.clone(), c);
+        self.to_tile.insert(c, tile);
+    }
+
+    pub fn get_char(&self, tile: &T) -> Option<char> {
+        self.to_char.get(tile).copied()
+    }
+
+    pub fn get_tile(&self, c: char) -> Option<&T> {
+        self.to_tile.get(&c)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextGridError {
+    Empty,
+    UnknownChar { c: char, line: usize, column: usize },
+    RaggedRow { line: usize, expected: usize, found: usize },
+}
+
+impl fmt::Display for TextGridError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextGridError::Empty => write!(f, "Text has no rows"),
+            TextGridError::UnknownChar { c, line, column } => {
+                write!(f, "'{c}' at line {line}, column {column} is not mapped to a tile")
+            }
+            TextGridError::RaggedRow { line, expected, found } => {
+                write!(f, "Line {line} is {found} tiles wide, expected {expected}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TextGridError {}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> Grid<T> {
+    // Line and column numbers in errors start from 1, counting the skipped blank lines
+    pub fn from_text(text: &str, char_map: &CharMap<T>) -> Result<Grid<T>, TextGridError> {
+        let mut cells = vec![];
+        let mut width = None;
+
+        for (line_i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let row_width = line.chars().count();
+            if *width.get_or_insert(row_width) != row_width {
+                return Err(TextGridError::RaggedRow {
+                    line: line_i + 1,
+                    expected: width.unwrap_or_default(),
+                    found: row_width,
+                });
+            }
+
+            for (column_i, c) in line.chars().enumerate() {
+                let tile = char_map.get_tile(c).ok_or(TextGridError::UnknownChar {
+                    c,
+                    line: line_i + 1,
+                    column: column_i + 1,
+                })?;
+                cells.push(tile.clone());
+            }
+        }
+
+        match width {
+            Some(width) => Ok(Grid::new(cells, width)),
+            None => Err(TextGridError::Empty),
+        }
+    }
+
+    // One line per row, each ending in a newline. Tiles missing from the map are drawn as '?'.
+    pub fn to_text(&self, char_map: &CharMap<T>) -> String {
+        let mut text = String::with_capacity(self.get_area() + self.get_height());
+        for row in self.get_cells().chunks(self.get_width().max(1)) {
+            text.extend(row.iter().map(|tile| char_map.get_char(tile).unwrap_or('?')));
+            text.push('\n');
+        }
+        text
+    }
+}
+
+// Prints each tile with its own Display, separated by spaces, one row per line
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send + fmt::Display> fmt::Display for Grid<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in self.get_cells().chunks(self.get_width().max(1)) {
+            for (tile_i, tile) in row.iter().enumerate() {
+                if tile_i > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{tile}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use super::grid::Grid;
+use super::pos::Direction;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use std::hash::Hash;
+
+// Which quarter turns of a tile are distinct from one another, and so get their own entry in the adjacency table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rotations {
+    None, // Symmetric under every rotation (or not allowed to be rotated), only the tile as given
+    Two,  // Symmetric under a half turn, such as a straight corridor
+    Four, // Every quarter turn is distinct, such as a corner or T-junction
+}
+
+// A tile as authored, with the socket along each of its edges. Two tiles may sit next to each other when the sockets
+// along their shared edge are equal. Sockets are only ever compared with plain equality, so an edge that has to meet a
+// mirrored or otherwise different partner (such as a socket "A" that only fits against "B") can't be expressed, and
+// rotating a tile doesn't reverse its sockets. Such edges need splitting out into symmetric sockets instead.
+#[derive(Debug, Clone)]
+pub struct TileDef<T: PartialEq + Eq + Hash + Clone + Sync + Send, S: PartialEq + Clone> {
+    pub tile: T,
+    // Indexed by Direction::index (Left, Right, Up, Down)
+    pub sockets: [S; 4],
+    pub rotations: Rotations,
+    // How likely this tile is to be picked, split evenly between its rotations
+    pub weight: f32,
+}
+
+pub struct Tileset<T: PartialEq + Eq + Hash + Clone + Sync + Send, S: PartialEq + Clone> {
+    pub tiles: Vec<TileDef<T, S>>,
+}
+
+// A single rotation of a tile, as placed into the generated grid. `rotation` is the number of clockwise quarter turns.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TileVariant<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    pub tile: T,
+    pub rotation: usize,
+}
+
+// The tileset with every rotation laid out as its own variant, and which variants may sit next to which
+pub struct AdjacencyTable<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    pub variants: Vec<TileVariant<T>>,
+    pub weights: Vec<f32>,
+    // allowed[direction.index()][a] holds every variant that may sit in `direction` from variant `a`
+    pub allowed: [Vec<Vec<usize>>; 4],
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send, S: PartialEq + Clone> Tileset<T, S> {
+    pub fn new(tiles: Vec<TileDef<T, S>>) -> Self {
+        Self { tiles }
+    }
+
+    pub fn compile(&self) -> AdjacencyTable<T> {
+        let mut variants = vec![];
+        let mut weights = vec![];
+        let mut variant_sockets: Vec<[S; 4]> = vec![];
+
+        for tile_def in self.tiles.iter() {
+            let rotation_count = match tile_def.rotations {
+                Rotations::None => 1,
+                Rotations::Two => 2,
+                Rotations::Four => 4,
+            };
+            let mut sockets = tile_def.sockets.clone();
+            for rotation in 0..rotation_count {
+                variants.push(TileVariant {
+                    tile: tile_def.tile.clone(),
+                    rotation,
+                });
+                weights.push(tile_def.weight / rotation_count as f32);
+                variant_sockets.push(sockets.clone());
+
+                // Each edge's socket moves round to the edge a quarter turn clockwise from it
+                let mut rotated = sockets.clone();
+                for direction in Direction::ALL {
+                    rotated[direction.clockwise().index()] = sockets[direction.index()].clone();
+                }
+                sockets = rotated;
+            }
+        }
+
+        let allowed = Direction::ALL.map(|direction| {
+            variant_sockets
+                .iter()
+                .map(|sockets| {
+                    (0..variant_sockets.len())
+                        .filter(|&other| {
+                            variant_sockets[other][direction.opposite().index()] == sockets[direction.index()]
+                        })
+                        .collect()
+                })
+                .collect()
+        });
+
+        AdjacencyTable {
+            variants,
+            weights,
+            allowed,
+        }
+    }
+}
+
+// Fills a width x height grid from the adjacency table. Each step the cell with the lowest entropy is collapsed to one
+// of its remaining variants, after which every variant that is left without a compatible neighbour is removed across
+// the grid. Should a cell run out of variants the whole grid is started over, up to `attempts` times.
+pub fn collapse_tiled<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    width: usize,
+    height: usize,
+    table: &AdjacencyTable<T>,
+    attempts: usize,
+) -> Option<Grid<TileVariant<T>>> {
+    let mut rng = rand::rngs::ThreadRng::default();
+    let area = width * height;
+    let variant_count = table.variants.len();
+
+    if variant_count == 0 || area == 0 {
+        return None;
+    }
+
+    let blank = Grid::new(vec![0; area], width);
+    let weight_log_weights = table
+        .weights
+        .iter()
+        .map(|&weight| if weight > 0.0 { weight * weight.ln() } else { 0.0 })
+        .collect::<Vec<_>>();
+
+    'attempts: for _ in 0..attempts {
+        // wave[i][v] - whether variant v can still be placed at cell i
+        let mut wave = vec![vec![true; variant_count]; area];
+        let mut remaining = vec![variant_count; area];
+        let mut sum_weights = vec![table.weights.iter().sum::<f32>(); area];
+        let mut sum_weight_log_weights = vec![weight_log_weights.iter().sum::<f32>(); area];
+
+        // supporters[i][v][d] - how many variants are still possible in the cell in direction d from i that allow v
+        // to sit next to them. Once this reaches zero v is no longer possible at i.
+        let initial_supporters = (0..variant_count)
+            .map(|variant| {
+                Direction::ALL.map(|direction| {
+                    (0..variant_count)
+                        .filter(|&other| table.allowed[direction.opposite().index()][other].contains(&variant))
+                        .count()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut supporters = vec![initial_supporters; area];
+
+        let mut banned: Vec<(usize, usize)> = vec![];
+
+        macro_rules! ban {
+            ($i: expr, $variant: expr) => {{
+                let (i, variant) = ($i, $variant);
+                wave[i][variant] = false;
+                supporters[i][variant] = [0; 4];
+                remaining[i] -= 1;
+                sum_weights[i] -= table.weights[variant];
+                sum_weight_log_weights[i] -= weight_log_weights[variant];
+                banned.push((i, variant));
+                if remaining[i] == 0 {
+                    continue 'attempts;
+                }
+            }};
+        }
+
+        macro_rules! propagate {
+            () => {
+                while let Some((i, variant)) = banned.pop() {
+                    let pos = blank.i_to_pos(i);
+                    for direction in Direction::ALL {
+                        let (dx, dy) = direction.offset();
+                        let neighbour_pos = pos.rel(dx, dy);
+                        if !blank.is_valid(&neighbour_pos) {
+                            continue;
+                        }
+                        let neighbour_i = blank.pos_to_i(&neighbour_pos);
+                        // The neighbour sits in `direction` from us, so we are its supporter in the opposite direction
+                        let support_direction = direction.opposite().index();
+                        for &other in table.allowed[direction.index()][variant].iter() {
+                            if !wave[neighbour_i][other] {
+                                continue;
+                            }
+                            supporters[neighbour_i][other][support_direction] -= 1;
+                            if supporters[neighbour_i][other][support_direction] == 0 {
+                                ban!(neighbour_i, other);
+                            }
+                        }
+                    }
+                }
+            };
+        }
+
+        // Cells along the edge have no neighbour on one side, so nothing there needs to support them
+        for i in 0..area {
+            let pos = blank.i_to_pos(i);
+            for direction in Direction::ALL {
+                let (dx, dy) = direction.offset();
+                if !blank.is_valid(&pos.rel(dx, dy)) {
+                    for variant in 0..variant_count {
+                        if wave[i][variant] {
+                            supporters[i][variant][direction.index()] = usize::MAX;
+                        }
+                    }
+                }
+            }
+            for variant in 0..variant_count {
+                if wave[i][variant] && supporters[i][variant].contains(&0) {
+                    ban!(i, variant);
+                }
+            }
+        }
+        propagate!();
+
+        loop {
+            // Pick the undecided cell with the lowest entropy, with a little noise to break ties
+            let mut lowest: Option<(usize, f32)> = None;
+            for i in 0..area {
+                if remaining[i] <= 1 {
+                    continue;
+                }
+                let entropy = sum_weights[i].ln() - sum_weight_log_weights[i] / sum_weights[i];
+                let entropy = entropy + rng.gen_range(0.0..1e-6);
+                match lowest {
+                    Some((_, lowest_entropy)) if entropy >= lowest_entropy => {}
+                    _ => lowest = Some((i, entropy)),
+                }
+            }
+
+            let i = match lowest {
+                Some((i, _)) => i,
+                None => break,
+            };
+
+            let options = (0..variant_count)
+                .filter(|&variant| wave[i][variant])
+                .collect::<Vec<_>>();
+            let chosen = match WeightedIndex::new(options.iter().map(|&variant| table.weights[variant])) {
+                Ok(weighted) => options[weighted.sample(&mut rng)],
+                Err(_) => options[rng.gen_range(0..options.len())],
+            };
+
+            for variant in options {
+                if variant != chosen {
+                    ban!(i, variant);
+                }
+            }
+            propagate!();
+        }
+
+        let cells = wave
+            .iter()
+            .map(|cell| {
+                let variant = cell
+                    .iter()
+                    .position(|&possible| possible)
+                    .expect("Every cell has a variant left");
+                table.variants[variant].clone()
+            })
+            .collect();
+        return Some(Grid::new(cells, width));
+    }
+
+    None
+}
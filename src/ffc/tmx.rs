@@ -0,0 +1,348 @@
+use super::grid::Grid;
+use base64::Engine;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::io::Read;
+use std::path::Path;
+
+// Reads and writes maps (.tmx) and tilesets (.tsx) made with the Tiled editor.
+//
+// Each tile layer is loaded as a Grid<u32> of global tile ids, with row 0 at the top of the map as in Tiled. A gid of
+// 0 is an empty cell, so a partially painted layer can be handed straight to collapse_rule as its starting grid with
+// 0 as `unset`, leaving the painted cells pinned in place. The flip flags Tiled stores in the top bits of each gid are
+// kept as they are, so a flipped tile is treated as a different tile to the unflipped one.
+
+#[derive(Debug)]
+pub enum TmxError {
+    Io(std::io::Error),
+    Xml(roxmltree::Error),
+    MissingAttribute(String, &'static str), // (element, attribute)
+    InvalidAttribute(&'static str, String), // (attribute, value)
+    InvalidData(String),
+    Unsupported(String),
+}
+
+impl fmt::Display for TmxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TmxError::Io(error) => write!(f, "Failed to read map: {error}"),
+            TmxError::Xml(error) => write!(f, "Failed to parse map: {error}"),
+            TmxError::MissingAttribute(element, attribute) => {
+                write!(f, "<{element}> is missing its '{attribute}' attribute")
+            }
+            TmxError::InvalidAttribute(attribute, value) => {
+                write!(f, "'{value}' is not a valid value for '{attribute}'")
+            }
+            TmxError::InvalidData(reason) => write!(f, "Invalid layer data: {reason}"),
+            TmxError::Unsupported(feature) => write!(f, "Unsupported: {feature}"),
+        }
+    }
+}
+
+impl std::error::Error for TmxError {}
+
+impl From<std::io::Error> for TmxError {
+    fn from(error: std::io::Error) -> Self {
+        TmxError::Io(error)
+    }
+}
+
+impl From<roxmltree::Error> for TmxError {
+    fn from(error: roxmltree::Error) -> Self {
+        TmxError::Xml(error)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TmxTilesetRef {
+    pub first_gid: u32,
+    // The tileset as written in the map, either a reference to a .tsx file or the whole embedded tileset, so that it
+    // can be written back out untouched
+    pub xml: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TmxLayer {
+    pub name: String,
+    pub grid: Grid<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TmxMap {
+    pub width: usize,
+    pub height: usize,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub orientation: String,
+    pub tilesets: Vec<TmxTilesetRef>,
+    pub layers: Vec<TmxLayer>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TsxTileset {
+    pub name: String,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub tile_count: u32,
+    pub columns: u32,
+    pub image: Option<String>,
+    // Each tile's custom properties, along with its class (stored as "class"), by local tile id
+    pub tile_properties: HashMap<u32, HashMap<String, String>>,
+}
+
+impl TmxMap {
+    // An empty map using the same tilesets and tile size as this one, for writing generated layers into
+    pub fn with_same_tilesets(&self, width: usize, height: usize) -> TmxMap {
+        TmxMap {
+            width,
+            height,
+            layers: vec![],
+            ..self.clone()
+        }
+    }
+
+    pub fn layer(&self, name: &str) -> Option<&Grid<u32>> {
+        self.layers
+            .iter()
+            .find(|layer| layer.name == name)
+            .map(|layer| &layer.grid)
+    }
+
+    // Replaces the layer with this name, or adds it on top if there isn't one, converting each tile to a gid
+    pub fn set_layer<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+        &mut self,
+        name: &str,
+        grid: &Grid<T>,
+        to_gid: impl Fn(&T) -> u32,
+    ) {
+        let grid = Grid::new(grid.get_cells().iter().map(to_gid).collect(), grid.get_width());
+        match self.layers.iter_mut().find(|layer| layer.name == name) {
+            Some(layer) => layer.grid = grid,
+            None => self.layers.push(TmxLayer {
+                name: name.to_owned(),
+                grid,
+            }),
+        }
+    }
+
+    // Writes the map back out as TMX, with each layer's data stored as CSV
+    pub fn to_tmx(&self) -> String {
+        let mut tmx = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        tmx += &format!(
+            "<map version=\"1.10\" orientation=\"{}\" renderorder=\"right-down\" width=\"{}\" height=\"{}\" \
+             tilewidth=\"{}\" tileheight=\"{}\" infinite=\"0\" nextlayerid=\"{}\" nextobjectid=\"1\">\n",
+            self.orientation,
+            self.width,
+            self.height,
+            self.tile_width,
+            self.tile_height,
+            self.layers.len() + 1
+        );
+        for tileset in self.tilesets.iter() {
+            tmx += &format!(" {}\n", tileset.xml);
+        }
+        for (layer_i, layer) in self.layers.iter().enumerate() {
+            tmx += &format!(
+                " <layer id=\"{}\" name=\"{}\" width=\"{}\" height=\"{}\">\n  <data encoding=\"csv\">\n",
+                layer_i + 1,
+                escape(&layer.name),
+                layer.grid.get_width(),
+                layer.grid.get_height()
+            );
+            let rows = layer
+                .grid
+                .get_cells()
+                .chunks(layer.grid.get_width())
+                .map(|row| row.iter().map(u32::to_string).collect::<Vec<_>>().join(","))
+                .collect::<Vec<_>>();
+            tmx += &rows.join(",\n");
+            tmx += "\n</data>\n </layer>\n";
+        }
+        tmx += "</map>\n";
+        tmx
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TmxError> {
+        Ok(std::fs::write(path, self.to_tmx())?)
+    }
+}
+
+pub fn load_tmx_file(path: impl AsRef<Path>) -> Result<TmxMap, TmxError> {
+    load_tmx(&std::fs::read_to_string(path)?)
+}
+
+pub fn load_tmx(xml: &str) -> Result<TmxMap, TmxError> {
+    let document = roxmltree::Document::parse(xml)?;
+    let map = document.root_element();
+
+    if map.attribute("infinite") == Some("1") {
+        return Err(TmxError::Unsupported(String::from("infinite maps")));
+    }
+
+    let tilesets = children(map, "tileset")
+        .into_iter()
+        .map(|tileset| {
+            Ok(TmxTilesetRef {
+                first_gid: parse_attribute(tileset, "firstgid")?,
+                xml: xml[tileset.range()].to_owned(),
+            })
+        })
+        .collect::<Result<Vec<_>, TmxError>>()?;
+
+    // Layers can be nested inside of groups, in which case they are flattened out in the order they appear
+    let layers = map
+        .descendants()
+        .filter(|node| node.has_tag_name("layer"))
+        .map(|layer| {
+            let width = parse_attribute::<usize>(layer, "width")?;
+            let height = parse_attribute::<usize>(layer, "height")?;
+            let data = children(layer, "data")
+                .into_iter()
+                .next()
+                .ok_or_else(|| TmxError::MissingAttribute(String::from("layer"), "data"))?;
+            let cells = read_layer_data(data, width * height)?;
+            Ok(TmxLayer {
+                name: layer.attribute("name").unwrap_or_default().to_owned(),
+                grid: Grid::new(cells, width),
+            })
+        })
+        .collect::<Result<Vec<_>, TmxError>>()?;
+
+    Ok(TmxMap {
+        width: parse_attribute(map, "width")?,
+        height: parse_attribute(map, "height")?,
+        tile_width: parse_attribute(map, "tilewidth")?,
+        tile_height: parse_attribute(map, "tileheight")?,
+        orientation: map.attribute("orientation").unwrap_or("orthogonal").to_owned(),
+        tilesets,
+        layers,
+    })
+}
+
+pub fn load_tsx_file(path: impl AsRef<Path>) -> Result<TsxTileset, TmxError> {
+    load_tsx(&std::fs::read_to_string(path)?)
+}
+
+// Also accepts a <tileset> embedded in a map, such as TmxTilesetRef::xml when it isn't a reference to a .tsx file
+pub fn load_tsx(xml: &str) -> Result<TsxTileset, TmxError> {
+    let document = roxmltree::Document::parse(xml)?;
+    let tileset = document.root_element();
+
+    if tileset.attribute("source").is_some() {
+        return Err(TmxError::Unsupported(String::from(
+            "tileset refers to an external .tsx file, load that instead",
+        )));
+    }
+
+    let mut tile_properties = HashMap::new();
+    for tile in children(tileset, "tile") {
+        let mut properties = children(tile, "properties")
+            .into_iter()
+            .flat_map(|properties| children(properties, "property"))
+            .map(|property| {
+                Ok((
+                    attribute(property, "name")?.to_owned(),
+                    property
+                        .attribute("value")
+                        .or_else(|| property.text())
+                        .unwrap_or_default()
+                        .to_owned(),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, TmxError>>()?;
+        // Tiled 1.9 renamed a tile's type to its class
+        if let Some(class) = tile.attribute("class").or_else(|| tile.attribute("type")) {
+            properties.insert(String::from("class"), class.to_owned());
+        }
+        tile_properties.insert(parse_attribute(tile, "id")?, properties);
+    }
+
+    Ok(TsxTileset {
+        name: tileset.attribute("name").unwrap_or_default().to_owned(),
+        tile_width: parse_attribute(tileset, "tilewidth")?,
+        tile_height: parse_attribute(tileset, "tileheight")?,
+        tile_count: parse_attribute(tileset, "tilecount")?,
+        columns: parse_attribute(tileset, "columns")?,
+        image: children(tileset, "image")
+            .into_iter()
+            .next()
+            .and_then(|image| image.attribute("source"))
+            .map(str::to_owned),
+        tile_properties,
+    })
+}
+
+fn read_layer_data(data: roxmltree::Node<'_, '_>, area: usize) -> Result<Vec<u32>, TmxError> {
+    let text = data.text().unwrap_or_default().trim();
+
+    let cells = match (data.attribute("encoding"), data.attribute("compression")) {
+        (Some("csv"), None) => text
+            .split(',')
+            .map(|gid| {
+                gid.trim()
+                    .parse::<u32>()
+                    .map_err(|_| TmxError::InvalidData(format!("'{}' is not a tile id", gid.trim())))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        (Some("base64"), compression) => {
+            let bytes = base64::engine::general_purpose::STANDARD
+                .decode(text.split_whitespace().collect::<String>())
+                .map_err(|error| TmxError::InvalidData(error.to_string()))?;
+            let mut decompressed = vec![];
+            match compression {
+                None => decompressed = bytes,
+                Some("zlib") => {
+                    flate2::read::ZlibDecoder::new(&bytes[..]).read_to_end(&mut decompressed)?;
+                }
+                Some("gzip") => {
+                    flate2::read::GzDecoder::new(&bytes[..]).read_to_end(&mut decompressed)?;
+                }
+                Some(other) => return Err(TmxError::Unsupported(format!("{other} compression"))),
+            }
+            decompressed
+                .chunks_exact(4)
+                .map(|gid| u32::from_le_bytes([gid[0], gid[1], gid[2], gid[3]]))
+                .collect()
+        }
+        (None, _) => children(data, "tile")
+            .into_iter()
+            .map(|tile| match tile.attribute("gid") {
+                Some(_) => parse_attribute(tile, "gid"),
+                None => Ok(0),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        (Some(other), _) => return Err(TmxError::Unsupported(format!("{other} encoding"))),
+    };
+
+    if cells.len() != area {
+        return Err(TmxError::InvalidData(format!(
+            "expected {area} tiles, found {}",
+            cells.len()
+        )));
+    }
+    Ok(cells)
+}
+
+fn children<'a, 'input>(node: roxmltree::Node<'a, 'input>, name: &'static str) -> Vec<roxmltree::Node<'a, 'input>> {
+    node.children().filter(|child| child.has_tag_name(name)).collect()
+}
+
+fn attribute<'a>(node: roxmltree::Node<'a, '_>, name: &'static str) -> Result<&'a str, TmxError> {
+    node.attribute(name)
+        .ok_or_else(|| TmxError::MissingAttribute(node.tag_name().name().to_owned(), name))
+}
+
+fn parse_attribute<V: std::str::FromStr>(node: roxmltree::Node<'_, '_>, name: &'static str) -> Result<V, TmxError> {
+    let value = attribute(node, name)?;
+    value
+        .parse()
+        .map_err(|_| TmxError::InvalidAttribute(name, value.to_owned()))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
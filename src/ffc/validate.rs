@@ -0,0 +1,133 @@
+use super::collapse_rules::{CollapseRule, Truth};
+use super::explain::{explain_rule, RuleTrace};
+use super::grid::Grid;
+use super::pos::Pos;
+use std::hash::Hash;
+
+// Checking a finished grid after the fact, such as in CI or after a map has been edited by hand, against either a
+// ruleset or the sample it was generated from.
+
+#[derive(Debug, Clone)]
+pub enum RuleViolation<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    // The cell was never set
+    Unset { pos: Pos },
+    // The cell holds a tile that isn't one of the options, so has no rule to check
+    UnknownTile { pos: Pos, tile: T },
+    // The tile's rule doesn't pass here, either failing outright (the trace's failures() points at the clauses that
+    // failed) or left unknown by unset cells around it
+    FailedRule { pos: Pos, tile: T, trace: RuleTrace<T> },
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> RuleViolation<T> {
+    pub fn pos(&self) -> &Pos {
+        match self {
+            RuleViolation::Unset { pos } => pos,
+            RuleViolation::UnknownTile { pos, .. } => pos,
+            RuleViolation::FailedRule { pos, .. } => pos,
+        }
+    }
+}
+
+// Every cell of `grid` that doesn't satisfy the rule for its tile. Rules are checked on the grid as it is, with the cell
+// holding its tile, and have to come out true. This is stricter than collapse_rule, which lets unknown results through
+// while cells are still unset, so rules left unknown by unset cells nearby are reported too.
+pub fn validate_rules<'a, T: PartialEq + Eq + Hash + Clone + Sync + Send + 'a>(
+    grid: &Grid<T>,
+    history_grid: &Grid<T>,
+    tile_options: &'a [T],
+    tile_to_rule: impl Fn(&'a T) -> &'a CollapseRule<T>,
+    unset: T,
+    outer: T,
+    max_depth: usize,
+) -> Vec<RuleViolation<T>> {
+    let mut violations = vec![];
+
+    for i in 0..grid.get_area() {
+        let pos = grid.i_to_pos(i);
+        let tile = grid.get(&pos, outer.clone());
+        if tile == unset {
+            violations.push(RuleViolation::Unset { pos });
+            continue;
+        }
+        let Some(tile_option) = tile_options.iter().find(|option| **option == tile) else {
+            violations.push(RuleViolation::UnknownTile { pos, tile });
+            continue;
+        };
+
+        let trace = explain_rule(
+            grid,
+            history_grid,
+            &pos,
+            tile_to_rule(tile_option),
+            unset.clone(),
+            outer.clone(),
+            max_depth,
+        );
+
+        if trace.result != Truth::True {
+            violations.push(RuleViolation::FailedRule { pos, tile, trace });
+        }
+    }
+    violations
+}
+
+#[derive(Debug, Clone)]
+pub enum PatternViolation<T: PartialEq + Eq + Hash + Clone + Sync + Send> {
+    // The cell was never set
+    Unset { pos: Pos },
+    // The cell's surrounding window doesn't appear anywhere in the sample. The window is (radius * 2 + 1) square, with
+    // `outer` past the edges of the grid.
+    Unmatched { pos: Pos, window: Grid<T> },
+}
+
+impl<T: PartialEq + Eq + Hash + Clone + Sync + Send> PatternViolation<T> {
+    pub fn pos(&self) -> &Pos {
+        match self {
+            PatternViolation::Unset { pos } => pos,
+            PatternViolation::Unmatched { pos, .. } => pos,
+        }
+    }
+}
+
+// Every cell of `grid` that is unset or whose window of `radius` can't be found in `pattern`. Windows are compared the
+// same way collapse compares them, so both sides read `outer` past their edges and unset cells in either match
+// anything, but as every unset cell in `grid` is reported by itself that can't let an unfinished grid through.
+pub fn validate_pattern<T: PartialEq + Eq + Hash + Clone + Sync + Send>(
+    grid: &Grid<T>,
+    pattern: &Grid<T>,
+    radius: isize,
+    unset: T,
+    outer: T,
+) -> Vec<PatternViolation<T>> {
+    let pattern_points = (0..pattern.get_area())
+        .map(|pattern_i| pattern.i_to_pos(pattern_i))
+        .collect::<Vec<_>>();
+    let size = (radius * 2 + 1) as usize;
+
+    (0..grid.get_area())
+        .map(|i| grid.i_to_pos(i))
+        .filter_map(|pos| {
+            if grid.get_ref(&pos, &outer) == &unset {
+                return Some(PatternViolation::Unset { pos });
+            }
+            if pattern_points
+                .iter()
+                .any(|p_pos| Grid::compare(pattern, p_pos, grid, &pos, radius, unset.clone(), outer.clone()))
+            {
+                return None;
+            }
+
+            let window = (0..size * size)
+                .map(|window_i| {
+                    let dx = (window_i % size) as isize - radius;
+                    let dy = (window_i / size) as isize - radius;
+                    grid.get(&pos.rel(dx, dy), outer.clone())
+                })
+                .collect();
+            Some(PatternViolation::Unmatched {
+                pos,
+                window: Grid::new(window, size),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,264 @@
+use super::pos::Direction;
+use super::tiled::{AdjacencyTable, TileVariant};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+// Loads tilesets written in the `data.xml` format used by the reference WFC implementation's simple tiled model:
+//
+//     <set>
+//       <tiles>
+//         <tile name="corner" symmetry="L" weight="0.5"/>
+//         ...
+//       </tiles>
+//       <neighbors>
+//         <neighbor left="corner 1" right="line"/>
+//         ...
+//       </neighbors>
+//       <subsets>
+//         <subset name="Simple"><tile name="corner"/>...</subset>
+//       </subsets>
+//     </set>
+//
+// Each tile is expanded into one variant per distinct orientation its symmetry class allows, numbered the same way as
+// in data.xml (so "corner 1" becomes TileVariant { tile: "corner", rotation: 1 }). For the F class, orientations 4 - 7
+// are the mirror images of 0 - 3.
+
+#[derive(Debug)]
+pub enum XmlTilesetError {
+    Io(std::io::Error),
+    Xml(roxmltree::Error),
+    MissingAttribute(String, &'static str), // (element, attribute)
+    InvalidAttribute(&'static str, String), // (attribute, value)
+    UnknownTile(String),
+    UnknownSubset(String),
+}
+
+impl fmt::Display for XmlTilesetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XmlTilesetError::Io(error) => write!(f, "Failed to read tileset: {error}"),
+            XmlTilesetError::Xml(error) => write!(f, "Failed to parse tileset: {error}"),
+            XmlTilesetError::MissingAttribute(element, attribute) => {
+                write!(f, "<{element}> is missing its '{attribute}' attribute")
+            }
+            XmlTilesetError::InvalidAttribute(attribute, value) => {
+                write!(f, "'{value}' is not a valid value for '{attribute}'")
+            }
+            XmlTilesetError::UnknownTile(name) => write!(f, "Neighbor refers to unknown tile '{name}'"),
+            XmlTilesetError::UnknownSubset(name) => write!(f, "No subset named '{name}'"),
+        }
+    }
+}
+
+impl std::error::Error for XmlTilesetError {}
+
+impl From<std::io::Error> for XmlTilesetError {
+    fn from(error: std::io::Error) -> Self {
+        XmlTilesetError::Io(error)
+    }
+}
+
+impl From<roxmltree::Error> for XmlTilesetError {
+    fn from(error: roxmltree::Error) -> Self {
+        XmlTilesetError::Xml(error)
+    }
+}
+
+pub fn load_xml_tileset_file(
+    path: impl AsRef<Path>,
+    subset: Option<&str>,
+) -> Result<AdjacencyTable<String>, XmlTilesetError> {
+    load_xml_tileset(&std::fs::read_to_string(path)?, subset)
+}
+
+// | subset
+// + When given, only the tiles listed under the <subset> with this name are loaded.
+pub fn load_xml_tileset(xml: &str, subset: Option<&str>) -> Result<AdjacencyTable<String>, XmlTilesetError> {
+    let document = roxmltree::Document::parse(xml)?;
+    let root = document.root_element();
+
+    let subset_tiles = match subset {
+        Some(subset_name) => {
+            let subset_node = children(root, "subsets")
+                .into_iter()
+                .flat_map(|subsets| children(subsets, "subset"))
+                .find(|node| node.attribute("name") == Some(subset_name))
+                .ok_or_else(|| XmlTilesetError::UnknownSubset(subset_name.to_owned()))?;
+            Some(
+                children(subset_node, "tile")
+                    .into_iter()
+                    .map(|tile| attribute(tile, "name").map(str::to_owned))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+        }
+        None => None,
+    };
+    let in_subset = |name: &str| match &subset_tiles {
+        Some(tiles) => tiles.iter().any(|tile| tile == name),
+        None => true,
+    };
+
+    let mut variants = vec![];
+    let mut weights = vec![];
+    // actions[v][k] - the variant v becomes after the k'th of the 8 rotations and reflections, see symmetry_actions
+    let mut actions: Vec<[usize; 8]> = vec![];
+    let mut first_variant = HashMap::<String, usize>::new();
+
+    for tile in children(root, "tiles")
+        .into_iter()
+        .flat_map(|tiles| children(tiles, "tile"))
+    {
+        let name = attribute(tile, "name")?;
+        if !in_subset(name) {
+            continue;
+        }
+
+        let symmetry = tile.attribute("symmetry").unwrap_or("X");
+        let weight = match tile.attribute("weight") {
+            Some(weight) => weight
+                .parse::<f32>()
+                .map_err(|_| XmlTilesetError::InvalidAttribute("weight", weight.to_owned()))?,
+            None => 1.0,
+        };
+
+        let first = variants.len();
+        let tile_actions = symmetry_actions(symmetry)
+            .ok_or_else(|| XmlTilesetError::InvalidAttribute("symmetry", symmetry.to_owned()))?;
+        for (rotation, tile_action) in tile_actions.into_iter().enumerate() {
+            variants.push(TileVariant {
+                tile: name.to_owned(),
+                rotation,
+            });
+            weights.push(weight);
+            actions.push(tile_action.map(|variant| first + variant));
+        }
+        first_variant.insert(name.to_owned(), first);
+    }
+
+    // Looks up a "name" or "name N" reference, which is None if the tile was left out by the subset
+    let variant_of = |reference: &str| -> Result<Option<usize>, XmlTilesetError> {
+        let mut parts = reference.split_whitespace();
+        let name = parts.next().unwrap_or_default();
+        // There are only 8 ways to rotate and reflect a tile
+        let orientation = match parts.next() {
+            Some(orientation) => orientation
+                .parse::<usize>()
+                .ok()
+                .filter(|&orientation| orientation < 8)
+                .ok_or_else(|| XmlTilesetError::InvalidAttribute("orientation", reference.to_owned()))?,
+            None => 0,
+        };
+        match first_variant.get(name) {
+            Some(&first) => Ok(Some(actions[first][orientation])),
+            None if in_subset(name) => Err(XmlTilesetError::UnknownTile(name.to_owned())),
+            None => Ok(None),
+        }
+    };
+
+    // dense[direction][a][b] - whether b may sit in `direction` from a
+    let variant_count = variants.len();
+    let mut dense = [(); 4].map(|_| vec![vec![false; variant_count]; variant_count]);
+    let (left, right, up, down) = (
+        Direction::Left.index(),
+        Direction::Right.index(),
+        Direction::Up.index(),
+        Direction::Down.index(),
+    );
+
+    for neighbor in children(root, "neighbors")
+        .into_iter()
+        .flat_map(|neighbors| children(neighbors, "neighbor"))
+    {
+        let (l, r) = match (
+            variant_of(attribute(neighbor, "left")?)?,
+            variant_of(attribute(neighbor, "right")?)?,
+        ) {
+            (Some(l), Some(r)) => (l, r),
+            _ => continue,
+        };
+
+        // The pair as given, and under each of the transformations that keep it side by side
+        dense[left][r][l] = true;
+        dense[left][actions[r][6]][actions[l][6]] = true;
+        dense[left][actions[l][4]][actions[r][4]] = true;
+        dense[left][actions[l][2]][actions[r][2]] = true;
+
+        // A quarter turn stacks the pair on top of one another. In data.xml the second row is at y + 1, which is Up.
+        let (d, u) = (actions[l][1], actions[r][1]);
+        dense[up][u][d] = true;
+        dense[up][actions[d][6]][actions[u][6]] = true;
+        dense[up][actions[u][4]][actions[d][4]] = true;
+        dense[up][actions[d][2]][actions[u][2]] = true;
+    }
+
+    // Right and down are just left and up seen from the other tile
+    dense[right] = transpose(&dense[left]);
+    dense[down] = transpose(&dense[up]);
+
+    let allowed = dense.map(|table| {
+        table
+            .iter()
+            .map(|row| (0..variant_count).filter(|&b| row[b]).collect())
+            .collect()
+    });
+
+    Ok(AdjacencyTable {
+        variants,
+        weights,
+        allowed,
+    })
+}
+
+// Maps an orientation onto the orientation it becomes under a rotation or reflection
+type Transform = fn(usize) -> usize;
+
+// For each orientation of a symmetry class, the orientations it becomes under each of the 8 ways of rotating and
+// reflecting a square: identity, 1 - 3 quarter turns, then a reflection followed by 0 - 3 quarter turns
+fn symmetry_actions(symmetry: &str) -> Option<Vec<[usize; 8]>> {
+    let (cardinality, rotate, reflect): (usize, Transform, Transform) = match symmetry {
+        "L" => (4, |i| (i + 1) % 4, |i| if i % 2 == 0 { i + 1 } else { i - 1 }),
+        "T" => (4, |i| (i + 1) % 4, |i| if i % 2 == 0 { i } else { 4 - i }),
+        "I" => (2, |i| 1 - i, |i| i),
+        "\\" => (2, |i| 1 - i, |i| 1 - i),
+        "F" => (
+            8,
+            |i| if i < 4 { (i + 1) % 4 } else { 4 + (i - 1) % 4 },
+            |i| if i < 4 { i + 4 } else { i - 4 },
+        ),
+        "X" => (1, |i| i, |i| i),
+        _ => return None,
+    };
+
+    Some(
+        (0..cardinality)
+            .map(|i| {
+                [
+                    i,
+                    rotate(i),
+                    rotate(rotate(i)),
+                    rotate(rotate(rotate(i))),
+                    reflect(i),
+                    reflect(rotate(i)),
+                    reflect(rotate(rotate(i))),
+                    reflect(rotate(rotate(rotate(i)))),
+                ]
+            })
+            .collect(),
+    )
+}
+
+fn children<'a, 'input>(node: roxmltree::Node<'a, 'input>, name: &'static str) -> Vec<roxmltree::Node<'a, 'input>> {
+    node.children().filter(|child| child.has_tag_name(name)).collect()
+}
+
+fn attribute<'a>(node: roxmltree::Node<'a, '_>, name: &'static str) -> Result<&'a str, XmlTilesetError> {
+    node.attribute(name)
+        .ok_or_else(|| XmlTilesetError::MissingAttribute(node.tag_name().name().to_owned(), name))
+}
+
+fn transpose(table: &[Vec<bool>]) -> Vec<Vec<bool>> {
+    (0..table.len())
+        .map(|b| table.iter().map(|row| row[b]).collect())
+        .collect()
+}
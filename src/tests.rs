@@ -28,3 +28,1797 @@ fn test_compare() -> Result<(), String> {
         Err(String::from("Compare failed"))
     }
 }
+
+#[test]
+fn test_collapse_rule_count_constraints() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{collapse_rule, CollapseRule};
+    use crate::ffc::constraints::GlobalConstraint;
+
+    // 0 - unset, 1 - outer, 2 - castle, 3 - water, 4 - grass
+    let tile_options = [2u8, 3, 4];
+    let rule = CollapseRule::True;
+    let constraints = [
+        GlobalConstraint::Count {
+            tile: 2,
+            min: 1,
+            max: 1,
+        },
+        GlobalConstraint::Fraction {
+            tile: 3,
+            min: 0.25,
+            max: 0.5,
+        },
+    ];
+
+    let generated = collapse_rule(
+        initialize(8, 8, 0u8),
+        &initialize(8, 8, 0u8),
+        &tile_options,
+        |_| &rule,
+        &[],
+        0,
+        1,
+        16,
+        1,
+        &constraints,
+    )
+    .ok_or("Failed to generate a grid")?;
+
+    let count = |tile| generated.get_cells().iter().filter(|&&cell| cell == tile).count();
+    if count(0) != 0 {
+        return Err(String::from("Grid was left with unset cells"));
+    }
+    if count(2) != 1 {
+        return Err(format!("Expected exactly one castle, found {}", count(2)));
+    }
+    if !(16..=32).contains(&count(3)) {
+        return Err(format!("Expected 16 to 32 water tiles, found {}", count(3)));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_fraction_constraint_bounds() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{collapse_rule, CollapseRule};
+    use crate::ffc::constraints::GlobalConstraint;
+
+    // 0 - unset, 1 - outer, 2 - water, 3 - grass
+    let tile_options = [2u8, 3];
+    let rule = CollapseRule::True;
+    let fraction = |min, max| [GlobalConstraint::Fraction { tile: 2, min, max }];
+
+    // 0.3 of 100 cells is exactly 30, which has to survive being worked out in floating point
+    if fraction(0.3, 0.3)[0].count_bounds(100).map(|(_, min, max)| (min, max)) != Some((30, 30)) {
+        return Err(String::from("Fraction of 0.3 didn't come to 30 of 100 cells"));
+    }
+    let generated = collapse_rule(
+        initialize(10, 10, 0u8),
+        &initialize(10, 10, 0u8),
+        &tile_options,
+        |_| &rule,
+        &[],
+        0,
+        1,
+        16,
+        1,
+        &fraction(0.3, 0.3),
+    )
+    .ok_or("Failed to generate a grid with an exact fraction")?;
+    let water = generated.get_cells().iter().filter(|&&cell| cell == 2).count();
+    if water != 30 {
+        return Err(format!("Expected exactly 30 water tiles, found {water}"));
+    }
+
+    // Bounds that cross can never be met, so the solve has to give up straight away rather than search
+    let crossed = collapse_rule(
+        initialize(10, 10, 0u8),
+        &initialize(10, 10, 0u8),
+        &tile_options,
+        |_| &rule,
+        &[],
+        0,
+        1,
+        16,
+        1,
+        &fraction(0.5, 0.4),
+    );
+    if crossed.is_some() {
+        return Err(String::from(
+            "Generated a grid for a fraction whose minimum is above its maximum",
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_count_constraint_look_ahead() -> Result<(), String> {
+    use crate::ffc::constraints::{constraints_allow, tile_counts, GlobalConstraint};
+
+    // 0 - unset, 2 - castle, 3 - water, 4 - grass
+    let constraints = [GlobalConstraint::Count {
+        tile: 3u8,
+        min: 3,
+        max: 4,
+    }];
+    let grid = Grid::new(
+        vec![
+            2, 0, //
+            0, 0, //
+        ],
+        2,
+    );
+    let counts = tile_counts(&grid);
+
+    // Every unset cell left has to become water for there to be enough of it
+    if constraints_allow(&grid, &counts, &constraints, &4, 0) {
+        return Err(String::from("Grass was allowed into a cell the water still needs"));
+    }
+    if !constraints_allow(&grid, &counts, &constraints, &3, 0) {
+        return Err(String::from(
+            "Water was rejected while it was still short of its minimum",
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_connected_constraint() -> Result<(), String> {
+    use crate::ffc::constraints::{check_constraint, tile_counts, GlobalConstraint};
+
+    // 0 - unset, 2 - floor, 3 - wall
+    let constraint = GlobalConstraint::Connected { walkable: vec![2u8] };
+
+    let split = Grid::new(
+        vec![
+            2, 3, 2, //
+            2, 3, 2, //
+            2, 3, 2, //
+        ],
+        3,
+    );
+    if check_constraint(&split, &tile_counts(&split), &constraint, 0, true) {
+        return Err(String::from("Split floor was reported as connected"));
+    }
+
+    // The gap in the wall has not been decided yet, so the floor could still become connected
+    let partial = Grid::new(
+        vec![
+            2, 3, 2, //
+            2, 0, 2, //
+            2, 3, 2, //
+        ],
+        3,
+    );
+    if !check_constraint(&partial, &tile_counts(&partial), &constraint, 0, false) {
+        return Err(String::from("Partially solved floor was rejected"));
+    }
+    if check_constraint(&partial, &tile_counts(&partial), &constraint, 0, true) {
+        return Err(String::from("Unset gap was treated as walkable on a complete grid"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_path_constraints() -> Result<(), String> {
+    use crate::ffc::constraints::{check_constraint, tile_counts, Edge, GlobalConstraint};
+
+    // 0 - unset, 2 - floor, 3 - wall, 4 - entrance, 5 - exit
+    let grid = Grid::new(
+        vec![
+            4, 2, 2, 2, //
+            3, 3, 3, 2, //
+            5, 2, 2, 2, //
+        ],
+        4,
+    );
+    let counts = tile_counts(&grid);
+
+    let path_length = |min, max| GlobalConstraint::PathLength {
+        walkable: vec![2u8],
+        from: 4,
+        to: 5,
+        min,
+        max,
+    };
+    if !check_constraint(&grid, &counts, &path_length(8, 8), 0, true) {
+        return Err(String::from("Path of 8 steps was not accepted"));
+    }
+    if check_constraint(&grid, &counts, &path_length(9, 20), 0, true) {
+        return Err(String::from("Path shorter than the minimum was accepted"));
+    }
+
+    let edge_path = |from, to| GlobalConstraint::EdgePath {
+        walkable: vec![2u8],
+        from,
+        to,
+    };
+    if !check_constraint(&grid, &counts, &edge_path(Edge::Top, Edge::Bottom), 0, true) {
+        return Err(String::from("Top to bottom path was not found"));
+    }
+    // The left column has no floor at all, so nothing can start there
+    if check_constraint(&grid, &counts, &edge_path(Edge::Left, Edge::Right), 0, true) {
+        return Err(String::from(
+            "Left to right path was found without any floor along the left edge",
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_constraints_after_placement() -> Result<(), String> {
+    use crate::ffc::constraints::{check_constraint, check_constraints_after, tile_counts, Edge, GlobalConstraint};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // 0 - unset, 2 - floor, 3 - wall, 4 - entrance, 5 - exit
+    let constraints = [
+        GlobalConstraint::Connected { walkable: vec![2u8] },
+        GlobalConstraint::Reachable {
+            walkable: vec![2],
+            tiles: vec![4, 5],
+        },
+        GlobalConstraint::PathLength {
+            walkable: vec![2],
+            from: 4,
+            to: 5,
+            min: 3,
+            max: 12,
+        },
+        GlobalConstraint::EdgePath {
+            walkable: vec![2],
+            from: Edge::Top,
+            to: Edge::Right,
+        },
+    ];
+
+    // Fill grids in at random, comparing the check around each placement with checking the whole grid for as long as
+    // the grid still passes
+    let mut rng = StdRng::seed_from_u64(7);
+    for _ in 0..200 {
+        let mut grid = initialize(6, 6, 0u8);
+        let mut order = (0..grid.get_area()).collect::<Vec<_>>();
+        rand::seq::SliceRandom::shuffle(order.as_mut_slice(), &mut rng);
+
+        let mut held = [true; 4];
+        for i in order {
+            grid.set(&grid.i_to_pos(i), [2, 2, 3, 4, 5][rng.gen_range(0..5)]);
+            let counts = tile_counts(&grid);
+            for (constraint_i, constraint) in constraints.iter().enumerate() {
+                if !held[constraint_i] {
+                    continue;
+                }
+                let whole = check_constraint(&grid, &counts, constraint, 0, false);
+                let after = check_constraints_after(&grid, &counts, std::slice::from_ref(constraint), 0, i);
+                if whole != after {
+                    return Err(format!(
+                        "Constraint {constraint_i} gave {after} after placing at {i}, not {whole}"
+                    ));
+                }
+                held[constraint_i] = whole;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_custom_rule() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{check_rule, CollapseRule};
+    use std::sync::Arc;
+
+    let heightmap = [0.1, 0.4, 0.8, 0.9];
+    let grid = initialize(4, 1, 0u8);
+
+    // Mountains only where the heightmap is high, and only next to other mountains
+    let rule = CollapseRule::And(vec![
+        CollapseRule::Custom(Arc::new(move |_, _, pos: &Pos| heightmap[pos.x as usize] > 0.5), 0),
+        CollapseRule::Left(Box::new(CollapseRule::Is(2))),
+    ]);
+
+    let allowed = (0..4)
+        .map(|x| check_rule(&grid, &grid, &Pos::new(x, 0), &rule, 0, 1, 16))
+        .collect::<Vec<_>>();
+    if allowed != [false, false, true, true] {
+        return Err(format!("Unexpected custom rule results {allowed:?}"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_compiled_rules_match_check_rule() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{evaluate_rule, CollapseRule};
+    use crate::ffc::compiled_rules::{compile_rule, evaluate_compiled_rule};
+    use rand::Rng;
+
+    let is = |tile| Box::new(CollapseRule::Is(tile));
+    let rules = [
+        CollapseRule::NextTo(Box::new(CollapseRule::Or(vec![
+            CollapseRule::Is(2),
+            CollapseRule::Is(3),
+        ]))),
+        CollapseRule::And(vec![
+            CollapseRule::True,
+            CollapseRule::Parenthesis(Box::new(CollapseRule::And(vec![
+                CollapseRule::Left(Box::new(CollapseRule::Up(is(2)))),
+                CollapseRule::Not(Box::new(CollapseRule::NextTo1(is(4)))),
+            ]))),
+        ]),
+        CollapseRule::Or(vec![
+            CollapseRule::False,
+            CollapseRule::Near(Box::new(CollapseRule::DownRight(is(3))), 1),
+            CollapseRule::Right(Box::new(CollapseRule::NextTo1(Box::new(CollapseRule::InBounds)))),
+        ]),
+        CollapseRule::Not(Box::new(CollapseRule::Not(Box::new(CollapseRule::Unset)))),
+        CollapseRule::NextTo1(Box::new(CollapseRule::Outer)),
+        // Deep enough to run into max_depth
+        CollapseRule::Left(Box::new(CollapseRule::Left(Box::new(CollapseRule::Left(is(2)))))),
+    ];
+
+    let mut rng = rand::thread_rng();
+    let grid = Grid::new((0..64).map(|_| rng.gen_range(0..5u8)).collect(), 8);
+
+    for (rule_i, rule) in rules.iter().enumerate() {
+        let compiled = compile_rule(rule, 3);
+        for i in 0..grid.get_area() {
+            let pos = grid.i_to_pos(i);
+            if evaluate_rule(&grid, &grid, &pos, rule, 0, 1, 3)
+                != evaluate_compiled_rule(&grid, &grid, &pos, &compiled, &0, &1)
+            {
+                return Err(format!("Rule {rule_i} differs once compiled at {pos:?}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_option_cache() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{collapse_rule_with_rng, solve_rules, CollapseRule, BACKTRACK_ATTEMPTS};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::sync::Arc;
+
+    // 0 - unset, 1 - outer, 2 - sand, 3 - water, 4 - rock
+    let tile_options = [2u8, 3, 4];
+    let rules = [
+        CollapseRule::Not(Box::new(CollapseRule::NextTo1(Box::new(CollapseRule::Is(2))))),
+        CollapseRule::NextTo1(Box::new(CollapseRule::Or(vec![
+            CollapseRule::Is(3),
+            CollapseRule::Unset,
+        ]))),
+        // Reads the corners, which none of the other rules do, so relies on its radius to be checked again when they
+        // change
+        CollapseRule::Custom(
+            Arc::new(|grid: &Grid<u8>, _: &Grid<u8>, pos: &Pos| {
+                [(-1, -1), (-1, 1), (1, -1), (1, 1)]
+                    .iter()
+                    .all(|&(dx, dy)| grid.get(&pos.rel(dx, dy), 1) != 4)
+            }),
+            1,
+        ),
+    ];
+    let tile_to_rule = |tile: &u8| &rules[*tile as usize - 2];
+
+    for seed in 0..8 {
+        let solve = |cache_options| {
+            solve_rules(
+                initialize(12, 12, 0u8),
+                &initialize(12, 12, 0u8),
+                &tile_options,
+                tile_to_rule,
+                &[],
+                0,
+                1,
+                16,
+                1,
+                &[],
+                BACKTRACK_ATTEMPTS,
+                None,
+                &mut StdRng::seed_from_u64(seed),
+                &mut |_, _| {},
+                cache_options,
+            )
+        };
+        let cached = collapse_rule_with_rng(
+            initialize(12, 12, 0u8),
+            &initialize(12, 12, 0u8),
+            &tile_options,
+            tile_to_rule,
+            &[],
+            0,
+            1,
+            16,
+            1,
+            &[],
+            BACKTRACK_ATTEMPTS,
+            None,
+            &mut StdRng::seed_from_u64(seed),
+            &mut |_, _| {},
+        );
+        let uncached = solve(false);
+        if cached.as_ref().map(|grid| grid.get_cells()) != uncached.as_ref().map(|grid| grid.get_cells()) {
+            return Err(format!("Cached and uncached solves differ with seed {seed}"));
+        }
+        if cached.as_ref().map(|grid| grid.get_cells()) != solve(true).as_ref().map(|grid| grid.get_cells()) {
+            return Err(format!("Solving twice with seed {seed} gave different grids"));
+        }
+
+        let grid = cached.ok_or(format!("Failed to generate a grid with seed {seed}"))?;
+        for i in 0..grid.get_area() {
+            let pos = grid.i_to_pos(i);
+            if grid.get(&pos, 1) == 4 && (grid.get(&pos.rel(1, 1), 1) == 4 || grid.get(&pos.rel(-1, 1), 1) == 4) {
+                return Err(format!("Rocks were placed corner to corner at {pos:?}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_rule_footprint() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{rule_footprint, CollapseRule};
+
+    let rule = CollapseRule::Or(vec![
+        CollapseRule::Left(Box::new(CollapseRule::Up(Box::new(CollapseRule::Is(2))))),
+        CollapseRule::NextTo1(Box::new(CollapseRule::Was(3))),
+        CollapseRule::Right(Box::new(CollapseRule::Right(Box::new(CollapseRule::Right(Box::new(
+            CollapseRule::Unset,
+        )))))),
+    ]);
+
+    let footprint = rule_footprint(&rule, 16);
+    if footprint != [(-1, 1), (3, 0)] {
+        return Err(format!("Unexpected footprint {footprint:?}"));
+    }
+
+    // The innermost Right is never evaluated with this little depth to work with
+    let footprint = rule_footprint(&rule, 4);
+    if footprint != [(-1, 1)] {
+        return Err(format!("Unexpected depth-limited footprint {footprint:?}"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_three_valued_rules() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{check_rule, evaluate_rule, CollapseRule, Truth};
+
+    // Anything but water to the left
+    let rule = CollapseRule::Left(Box::new(CollapseRule::Not(Box::new(CollapseRule::Is(2)))));
+
+    let grid = Grid::new(vec![0, 0, 3, 0, 2, 0], 2);
+    let results = (0..3)
+        .map(|y| evaluate_rule(&grid, &grid, &Pos::new(1, y), &rule, 0, 1, 16))
+        .collect::<Vec<_>>();
+    if results != [Truth::Unknown, Truth::True, Truth::False] {
+        return Err(format!("Unexpected results {results:?}"));
+    }
+
+    // An unset neighbour could still become something other than water, so the solver must not rule it out
+    if !check_rule(&grid, &grid, &Pos::new(1, 0), &rule, 0, 1, 16) {
+        return Err(String::from("Negated rule rejected an unset neighbour"));
+    }
+
+    let outer_results = [CollapseRule::Outer, CollapseRule::Unset, CollapseRule::InBounds]
+        .iter()
+        .map(|rule| evaluate_rule(&grid, &grid, &Pos::new(-1, 0), rule, 0, 1, 16))
+        .collect::<Vec<_>>();
+    if outer_results != [Truth::True, Truth::False, Truth::False] {
+        return Err(format!("Unexpected results outside of the grid {outer_results:?}"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_analyze_rules() -> Result<(), String> {
+    use crate::ffc::analysis::analyze_rules;
+    use crate::ffc::collapse_rules::CollapseRule;
+
+    let next_to_any = |tiles: &[u8]| {
+        CollapseRule::NextTo(Box::new(CollapseRule::Or(
+            tiles
+                .iter()
+                .map(|&tile| CollapseRule::Is(tile))
+                .chain([CollapseRule::Outer])
+                .collect(),
+        )))
+    };
+
+    // 2 and 3 get along, 4 only likes itself, 5 can never be placed and 6 can't stand anything next to it. 7 fails on
+    // a blank grid but not once its left neighbour is set, and 8 needs a history that has no 9 in it.
+    let rules = [
+        next_to_any(&[2, 3]),
+        next_to_any(&[2, 3]),
+        next_to_any(&[4]),
+        CollapseRule::False,
+        CollapseRule::NextTo(Box::new(CollapseRule::Not(Box::new(CollapseRule::Or(
+            (2..=7).map(CollapseRule::Is).collect(),
+        ))))),
+        CollapseRule::Left(Box::new(CollapseRule::Not(Box::new(CollapseRule::Unset)))),
+        CollapseRule::Was(9),
+    ];
+    let tile_options = [2u8, 3, 4, 5, 6, 7];
+    let history = initialize(6, 6, 2u8);
+
+    let analysis = analyze_rules(&tile_options, |&tile| &rules[tile as usize - 2], &history, 0, 1, 4);
+
+    if analysis.unplaceable != [5] {
+        return Err(format!("Unexpected unplaceable tiles {:?}", analysis.unplaceable));
+    }
+    if analysis.isolated != [6] {
+        return Err(format!("Unexpected isolated tiles {:?}", analysis.isolated));
+    }
+    if !analysis.too_deep.is_empty() {
+        return Err(format!("Unexpected too deep tiles {:?}", analysis.too_deep));
+    }
+    if !analysis.incompatible.contains(&(2, 4)) || analysis.incompatible.contains(&(2, 3)) {
+        return Err(format!("Unexpected incompatible pairs {:?}", analysis.incompatible));
+    }
+
+    if analysis.unchecked != [7] {
+        return Err(format!("Unexpected unchecked tiles {:?}", analysis.unchecked));
+    }
+
+    // 2 can only go next to 7, whose rule can't be judged, so 2 isn't known to be isolated
+    let unchecked_rules = [
+        next_to_any(&[7]),
+        CollapseRule::Left(Box::new(CollapseRule::Not(Box::new(CollapseRule::Unset)))),
+    ];
+    let beside_unchecked = analyze_rules(
+        &[2u8, 7],
+        |&tile| &unchecked_rules[usize::from(tile == 7)],
+        &history,
+        0,
+        1,
+        4,
+    );
+    if !beside_unchecked.isolated.is_empty() {
+        return Err(format!(
+            "Tiles reported isolated beside an unchecked tile {:?}",
+            beside_unchecked.isolated
+        ));
+    }
+
+    let shallow = analyze_rules(&tile_options, |&tile| &rules[tile as usize - 2], &history, 0, 1, 3);
+    if shallow.too_deep != [6] {
+        return Err(format!("Unexpected too deep tiles {:?}", shallow.too_deep));
+    }
+
+    // Only the history can rule out 8
+    let with_history = analyze_rules(&[2u8, 8], |&tile| &rules[tile as usize - 2], &history, 0, 1, 4);
+    if with_history.unplaceable != [8] {
+        return Err(format!("Unexpected unplaceable tiles {:?}", with_history.unplaceable));
+    }
+    let blank_history = analyze_rules(
+        &[2u8, 8],
+        |&tile| &rules[tile as usize - 2],
+        &initialize(6, 6, 0),
+        0,
+        1,
+        4,
+    );
+    if !blank_history.unplaceable.is_empty() {
+        return Err(format!("Unexpected unplaceable tiles {:?}", blank_history.unplaceable));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_explain_rule() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{evaluate_rule, CollapseRule, Truth};
+    use crate::ffc::explain::explain_rule;
+
+    // Sand must only be next to water or sand
+    let rule = CollapseRule::NextTo(Box::new(CollapseRule::Or(vec![
+        CollapseRule::Is(2),
+        CollapseRule::Is(3),
+    ])));
+    let grid = Grid::new(
+        vec![
+            2, 2, 2, //
+            2, 3, 4, //
+            2, 0, 2, //
+        ],
+        3,
+    );
+
+    let pos = Pos::new(1, 1);
+    let trace = explain_rule(&grid, &grid, &pos, &rule, 0, 1, 16);
+    if trace.result != evaluate_rule(&grid, &grid, &pos, &rule, 0, 1, 16) || trace.result != Truth::False {
+        return Err(format!("Unexpected result {:?}", trace.result));
+    }
+
+    // Only the clauses to the right (reading the 4) should be blamed
+    let failures = trace.failures();
+    if failures.len() != 2
+        || failures.iter().any(|failure| {
+            failure.rule != "Is" || failure.pos.x != 2 || failure.pos.y != 1 || failure.inspected[0].1 != 4
+        })
+    {
+        return Err(format!("Unexpected failures:\n{trace}"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_learn_rules() -> Result<(), String> {
+    use crate::ffc::collapse_rules::collapse_rule;
+    use crate::ffc::learn::learn_rules;
+
+    // An island, so water (2) is always surrounded by sand (3), and sand always sits between water and grass (4)
+    let sample = Grid::new(
+        vec![
+            4, 4, 4, 4, 4, //
+            4, 3, 3, 3, 4, //
+            4, 3, 2, 3, 4, //
+            4, 3, 3, 3, 4, //
+            4, 4, 4, 4, 4, //
+        ],
+        5,
+    );
+
+    let learned = learn_rules(&sample, 0, None);
+    if learned.tiles != vec![4, 3, 2] || learned.weights != vec![0.64, 0.32, 0.04] {
+        return Err(format!(
+            "Unexpected tiles {:?} with weights {:?}",
+            learned.tiles, learned.weights
+        ));
+    }
+
+    let generated = collapse_rule(
+        initialize(8, 8, 0u8),
+        &initialize(8, 8, 0u8),
+        &learned.tiles,
+        |tile| &learned.rules[tile],
+        &learned.weights,
+        0,
+        1,
+        16,
+        1,
+        &[],
+    )
+    .ok_or("Failed to generate a grid")?;
+
+    // Water was never seen next to water or grass
+    for i in 0..generated.get_area() {
+        let pos = generated.i_to_pos(i);
+        if generated.get(&pos, 1) != 2 {
+            continue;
+        }
+        for neighbour_pos in [pos.rel(0, 1), pos.rel(0, -1), pos.rel(1, 0), pos.rel(-1, 0)] {
+            let neighbour = generated.get(&neighbour_pos, 1);
+            if neighbour == 2 || neighbour == 4 {
+                return Err(format!(
+                    "Unexpected {neighbour} next to water at ({}, {})",
+                    pos.x, pos.y
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_learn_rules_from_stripes() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{collapse_rule, collapse_rule_with_rng, BACKTRACK_ATTEMPTS};
+    use crate::ffc::learn::learn_rules;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Each row of water (2) is followed by sand (3), and each row of sand by grass (4), which fills the rest. This
+    // leaves the solver far less room than the island, so it is seeded to keep the test repeatable.
+    let sample = Grid::new(
+        vec![
+            2, 2, 2, 2, //
+            3, 3, 3, 3, //
+            4, 4, 4, 4, //
+            4, 4, 4, 4, //
+        ],
+        4,
+    );
+
+    let learned = learn_rules(&sample, 0, None);
+    if learned.tiles != vec![2, 3, 4] || learned.weights != vec![0.25, 0.25, 0.5] {
+        return Err(format!(
+            "Unexpected tiles {:?} with weights {:?}",
+            learned.tiles, learned.weights
+        ));
+    }
+
+    for seed in 0..8 {
+        let generated = collapse_rule_with_rng(
+            initialize(8, 8, 0u8),
+            &initialize(8, 8, 0u8),
+            &learned.tiles,
+            |tile| &learned.rules[tile],
+            &learned.weights,
+            0,
+            1,
+            16,
+            1,
+            &[],
+            BACKTRACK_ATTEMPTS,
+            None,
+            &mut StdRng::seed_from_u64(seed),
+            &mut |_, _| {},
+        )
+        .ok_or(format!("Failed to generate a grid with seed {seed}"))?;
+
+        // Every pair of cells one row apart in the output must have been seen in the sample
+        for i in 0..generated.get_area() {
+            let pos = generated.i_to_pos(i);
+            let (tile, next) = (generated.get(&pos, 1), generated.get(&pos.rel(0, 1), 1));
+            if next != 1 && !matches!((tile, next), (2, 2) | (2, 3) | (3, 4) | (4, 4)) {
+                return Err(format!("Unexpected {next} after {tile} at ({}, {})", pos.x, pos.y));
+            }
+        }
+    }
+
+    // A weight missing for one of the tiles can't be picked from
+    let generated = collapse_rule(
+        initialize(8, 8, 0u8),
+        &initialize(8, 8, 0u8),
+        &learned.tiles,
+        |tile| &learned.rules[tile],
+        &learned.weights[..2],
+        0,
+        1,
+        16,
+        1,
+        &[],
+    );
+    if generated.is_some() {
+        return Err(String::from("Generated a grid without a weight for every tile"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_tiled_model() -> Result<(), String> {
+    use crate::ffc::tiled::{collapse_tiled, Rotations, TileDef, Tileset};
+
+    // Pipes, where each socket is whether a pipe leaves through that edge (Left, Right, Up, Down)
+    let tileset = Tileset::new(vec![
+        TileDef {
+            tile: "empty",
+            sockets: [false, false, false, false],
+            rotations: Rotations::None,
+            weight: 1.0,
+        },
+        TileDef {
+            tile: "straight",
+            sockets: [true, true, false, false],
+            rotations: Rotations::Two,
+            weight: 1.0,
+        },
+        TileDef {
+            tile: "corner",
+            sockets: [true, false, true, false],
+            rotations: Rotations::Four,
+            weight: 1.0,
+        },
+    ]);
+
+    let table = tileset.compile();
+    if table.variants.len() != 7 {
+        return Err(format!("Expected 7 variants, found {}", table.variants.len()));
+    }
+
+    // A quarter turn stands the straight pipe on end, so it must carry on upwards, while the flat one must not
+    let up = Direction::Up.index();
+    if !table.allowed[up][2].contains(&2) || table.allowed[up][2].contains(&0) || table.allowed[up][1].contains(&2) {
+        return Err(String::from("Rotated sockets did not line up"));
+    }
+
+    let generated = collapse_tiled(12, 12, &table, 16).ok_or("Failed to generate a grid")?;
+    let variant_i = |pos: &Pos| {
+        let cell = &generated.get_cells()[generated.pos_to_i(pos)];
+        table.variants.iter().position(|variant| variant == cell).unwrap()
+    };
+    for i in 0..generated.get_area() {
+        let pos = generated.i_to_pos(i);
+        for direction in Direction::ALL {
+            let (dx, dy) = direction.offset();
+            let neighbour_pos = pos.rel(dx, dy);
+            if generated.is_valid(&neighbour_pos)
+                && !table.allowed[direction.index()][variant_i(&pos)].contains(&variant_i(&neighbour_pos))
+            {
+                return Err(format!("Mismatched sockets at ({}, {})", pos.x, pos.y));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn test_load_xml_tileset() -> Result<(), String> {
+    use crate::ffc::tiled::collapse_tiled;
+    use crate::ffc::xml::load_xml_tileset;
+
+    // Lines that may only end by running into the edge of the grid
+    let xml = r#"
+        <set>
+            <tiles>
+                <tile name="empty" symmetry="X"/>
+                <tile name="line" symmetry="I" weight="0.5"/>
+            </tiles>
+            <neighbors>
+                <neighbor left="empty" right="empty"/>
+                <neighbor left="line" right="line"/>
+                <neighbor left="line 1" right="empty"/>
+            </neighbors>
+        </set>
+    "#;
+
+    let table = load_xml_tileset(xml, None).map_err(|error| error.to_string())?;
+    if table.variants.len() != 3 || table.weights != vec![1.0, 0.5, 0.5] {
+        return Err(format!("Unexpected variants {:?}", table.variants));
+    }
+
+    // Variants are empty, then the line lying flat, then the line stood on end
+    let (right, up) = (Direction::Right.index(), Direction::Up.index());
+    let expected = [
+        (right, 0, vec![0, 2]),
+        (right, 1, vec![1]),
+        (right, 2, vec![0]),
+        (up, 0, vec![0, 1]),
+        (up, 1, vec![0]),
+        (up, 2, vec![2]),
+    ];
+    for (direction, variant, allowed) in expected {
+        if table.allowed[direction][variant] != allowed {
+            return Err(format!(
+                "Expected {allowed:?} in direction {direction} from {variant}, found {:?}",
+                table.allowed[direction][variant]
+            ));
+        }
+    }
+
+    collapse_tiled(8, 8, &table, 16).ok_or("Failed to generate a grid")?;
+
+    if !matches!(
+        load_xml_tileset(&xml.replace("line 1", "line 8"), None),
+        Err(crate::ffc::xml::XmlTilesetError::InvalidAttribute("orientation", _))
+    ) {
+        return Err(String::from("Expected an invalid orientation error"));
+    }
+
+    match load_xml_tileset(xml, Some("Missing")) {
+        Err(crate::ffc::xml::XmlTilesetError::UnknownSubset(_)) => Ok(()),
+        _ => Err(String::from("Expected an unknown subset error")),
+    }
+}
+
+#[cfg(feature = "tmx")]
+#[test]
+fn test_tmx_round_trip() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{collapse_rule, CollapseRule};
+    use crate::ffc::tmx::load_tmx;
+
+    // A 4x3 map where the designer has only painted the border of the top row
+    let tmx = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <map version="1.10" orientation="orthogonal" width="4" height="3" tilewidth="16" tileheight="16" infinite="0">
+            <tileset firstgid="1" source="terrain.tsx"/>
+            <layer id="1" name="Ground" width="4" height="3">
+                <data encoding="csv">
+                    1,0,0,2,
+                    0,0,0,0,
+                    0,0,0,0
+                </data>
+            </layer>
+            <layer id="2" name="Detail" width="4" height="3">
+                <data encoding="base64">AwAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA</data>
+            </layer>
+        </map>"#;
+
+    let map = load_tmx(tmx).map_err(|error| error.to_string())?;
+    let ground = map.layer("Ground").ok_or("Missing ground layer")?;
+    if ground.get_cells() != &vec![1, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0] {
+        return Err(format!("Unexpected ground layer {:?}", ground.get_cells()));
+    }
+    if map.layer("Detail").ok_or("Missing detail layer")?.get_cells()[0] != 3 {
+        return Err(String::from("Failed to decode the base64 layer"));
+    }
+
+    // The painted cells are pinned, everything else gets filled in
+    let rule = CollapseRule::True;
+    let generated = collapse_rule(
+        ground.clone(),
+        &initialize(4, 3, 0u32),
+        &[1, 2],
+        |_| &rule,
+        &[],
+        0,
+        u32::MAX,
+        16,
+        1,
+        &[],
+    )
+    .ok_or("Failed to generate a grid")?;
+
+    let mut output = map.with_same_tilesets(4, 3);
+    output.set_layer("Ground", &generated, |&gid| gid);
+    let reloaded = load_tmx(&output.to_tmx()).map_err(|error| error.to_string())?;
+
+    let reloaded_ground = reloaded.layer("Ground").ok_or("Missing ground layer after saving")?;
+    if reloaded_ground.get_cells() != generated.get_cells() {
+        return Err(format!("Layer changed after saving {:?}", reloaded_ground.get_cells()));
+    }
+    if reloaded_ground.get_cells()[0] != 1 || reloaded_ground.get_cells()[3] != 2 {
+        return Err(String::from("Pinned cells were not kept"));
+    }
+    if reloaded.tilesets.len() != 1 || !reloaded.tilesets[0].xml.contains("terrain.tsx") {
+        return Err(String::from("Tileset was not carried over"));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn test_image_round_trip() -> Result<(), String> {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+
+    // A 16-bit image, where the two transparent pixels differ only in their (invisible) color
+    let source = ImageBuffer::from_fn(3, 2, |x, y| match (x, y) {
+        (0, 0) => Rgba([u16::MAX, 0, 0, u16::MAX]),
+        (1, 0) => Rgba([0, u16::MAX, 0, u16::MAX]),
+        (2, 0) => Rgba([u16::MAX, 0, 0, 0]),
+        (2, 1) => Rgba([0, 0, u16::MAX, 0]),
+        _ => Rgba([u16::MAX, 0, 0, u16::MAX]),
+    });
+
+    let (grid, palette) = Grid::from_image(&DynamicImage::ImageRgba16(source), 2);
+    if grid.get_cells() != &vec![2, 3, 4, 2, 2, 4] || palette.ids() != vec![2, 3, 4] {
+        return Err(format!("Unexpected ids {:?}", grid.get_cells()));
+    }
+
+    let image = grid.to_image(&palette);
+    if image.get_pixel(1, 0) != &Rgba([0, 255, 0, 255]) || image.get_pixel(2, 1) != &Rgba([0, 0, 0, 0]) {
+        return Err(String::from("Colors did not survive the round trip"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_text_grid() -> Result<(), String> {
+    use crate::ffc::text::{CharMap, TextGridError};
+
+    let char_map = CharMap::new([('~', 2u8), ('.', 3), ('#', 4)]);
+    let grid = Grid::from_text(
+        "
+        ~~..
+        ~.##
+        ",
+        &char_map,
+    )
+    .map_err(|error| error.to_string())?;
+
+    if grid.get_width() != 4 || grid.get(&Pos::new(1, 1), 0) != 3 || grid.get(&Pos::new(3, 1), 0) != 4 {
+        return Err(format!("Unexpected grid {:?}", grid.get_cells()));
+    }
+    if grid.to_text(&char_map) != "~~..\n~.##\n" {
+        return Err(format!("Unexpected text\n{}", grid.to_text(&char_map)));
+    }
+    if grid.to_string() != "2 2 3 3\n2 3 4 4\n" {
+        return Err(format!("Unexpected display\n{grid}"));
+    }
+
+    match Grid::from_text("~~\n~.#", &char_map) {
+        Err(TextGridError::RaggedRow {
+            line: 2,
+            expected: 2,
+            found: 3,
+        }) => {}
+        other => return Err(format!("Expected a ragged row error, found {other:?}")),
+    }
+    match Grid::from_text("~x", &char_map) {
+        Err(TextGridError::UnknownChar { c: 'x', .. }) => Ok(()),
+        other => Err(format!("Expected an unknown char error, found {other:?}")),
+    }
+}
+
+#[test]
+fn test_recorder() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{collapse_rule_observed, CollapseRule};
+    use crate::ffc::recorder::Recorder;
+
+    let rule = CollapseRule::True;
+    let mut recorder = Recorder::new(4);
+    let generated = collapse_rule_observed(
+        initialize(4, 4, 0u8),
+        &initialize(4, 4, 0u8),
+        &[2, 3],
+        |_| &rule,
+        &[],
+        0,
+        1,
+        16,
+        1,
+        &[],
+        &mut |grid, event| recorder.observe(grid, event),
+    )
+    .ok_or("Failed to generate a grid")?;
+
+    // One frame for every 4 of the 16 placements, plus the final grid
+    if recorder.frames.len() != 5 {
+        return Err(format!("Expected 5 frames, found {}", recorder.frames.len()));
+    }
+    if recorder.frames.last().unwrap().grid.get_cells() != generated.get_cells() {
+        return Err(String::from("Last frame is not the finished grid"));
+    }
+    let set_in_first_frame = recorder.frames[0]
+        .grid
+        .get_cells()
+        .iter()
+        .filter(|&&tile| tile != 0)
+        .count();
+    if set_in_first_frame != 4 {
+        return Err(format!(
+            "Expected 4 cells set in the first frame, found {set_in_first_frame}"
+        ));
+    }
+
+    #[cfg(feature = "image")]
+    {
+        let path = std::env::temp_dir().join(format!("ffc_test_recorder_{}.png", std::process::id()));
+        let color_of = |tile: &u8| image::Rgba([*tile * 60, 0, 0, 255]);
+        recorder
+            .write_apng(&path, color_of, image::Rgba([255, 0, 0, 255]), 2, 100)
+            .map_err(|error| error.to_string())?;
+        let written = std::fs::read(&path).map_err(|error| error.to_string())?;
+        std::fs::remove_file(&path).ok();
+        if !written.windows(4).any(|chunk| chunk == b"acTL") {
+            return Err(String::from("Written PNG is not animated"));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_layered_grid() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{evaluate_rule, CollapseRule, Truth};
+    use crate::ffc::compiled_rules::{compile_rule, evaluate_compiled_rule};
+    use crate::ffc::layers::{collapse_layers, collapse_layers_jointly, LayeredGrid};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::sync::Arc;
+
+    // Terrain is grass (2) or water (3), with trees (5) only on grass, never side by side, and nothing (4) anywhere
+    let layer_options = vec![vec![2u8, 3], vec![4, 5]];
+    let layer_rule = |layer: usize, tile: &u8| match (layer, *tile) {
+        (1, 5) => CollapseRule::And(vec![
+            CollapseRule::InLayer(0, Box::new(CollapseRule::Is(2))),
+            CollapseRule::Custom(
+                Arc::new(|grid: &Grid<u8>, _: &Grid<u8>, pos: &Pos| {
+                    grid.get(&pos.rel(-1, 0), 1) != 5 && grid.get(&pos.rel(1, 0), 1) != 5
+                }),
+                1,
+            ),
+        ]),
+        _ => CollapseRule::True,
+    };
+    let history = LayeredGrid::filled(6, 6, 2, 0u8);
+
+    let check = |layered: &LayeredGrid<u8>| {
+        for (i, object) in layered.layer(1).get_cells().iter().enumerate() {
+            if *object == 5 && layered.layer(0).get_cells()[i] != 2 {
+                return Err(format!("Tree on {} at {i}", layered.layer(0).get_cells()[i]));
+            }
+            let pos = layered.layer(1).i_to_pos(i);
+            if *object == 5 && layered.layer(1).get(&pos.rel(1, 0), 1) == 5 {
+                return Err(format!("Trees side by side at {i}"));
+            }
+        }
+        Ok(())
+    };
+
+    // Without any layers to read, InLayer can't say either way
+    let rule = CollapseRule::InLayer(0, Box::new(CollapseRule::Is(2u8)));
+    let grid = initialize(3, 3, 2u8);
+    if evaluate_rule(&grid, &grid, &Pos::new(1, 1), &rule, 0, 1, 8) != Truth::Unknown
+        || evaluate_compiled_rule(&grid, &grid, &Pos::new(1, 1), &compile_rule(&rule, 8), &0, &1) != Truth::Unknown
+    {
+        return Err(String::from("InLayer was decided outside of a layered solve"));
+    }
+
+    let sequenced = collapse_layers(
+        history.clone(),
+        &history,
+        &layer_options,
+        layer_rule,
+        0,
+        1,
+        8,
+        1,
+        &mut StdRng::seed_from_u64(1),
+    )
+    .ok_or("Sequenced layers failed to collapse")?;
+    check(&sequenced)?;
+
+    // Placing a tree up front means the terrain below it has to be grass, which only solving jointly can account for.
+    // The tree's neighbours have to see it too, or one of them can take a tree and leave it nowhere to go.
+    let mut pinned = history.clone();
+    pinned.layers[1].set(&Pos::new(2, 3), 5);
+    for seed in 0..20 {
+        let joint = collapse_layers_jointly(
+            pinned.clone(),
+            &history,
+            &layer_options,
+            layer_rule,
+            0,
+            1,
+            8,
+            1,
+            &mut StdRng::seed_from_u64(seed),
+        )
+        .ok_or(format!("Joint layers failed to collapse with seed {seed}"))?;
+        check(&joint)?;
+        if joint.layer(1).get(&Pos::new(2, 3), 0) != 5 || joint.layer(0).get(&Pos::new(2, 3), 0) != 2 {
+            return Err(String::from("Pinned tree was not kept"));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_collapse_hierarchical() -> Result<(), String> {
+    use crate::ffc::collapse_rules::CollapseRule;
+    use crate::ffc::hierarchy::collapse_hierarchical;
+
+    // Land (2) and sea (3) biomes, expanded into grass (4) and forest (5) on land, water (6) at sea and sand (7) in
+    // either
+    let coarse = Grid::new(
+        vec![
+            2, 2, 3, //
+            2, 3, 3, //
+        ],
+        3,
+    );
+    let fine = collapse_hierarchical(
+        &coarse,
+        4,
+        &[4u8, 5, 6, 7],
+        |parent, tile| match (*parent, *tile) {
+            (2, 4) | (2, 5) | (3, 6) | (_, 7) => CollapseRule::True,
+            _ => CollapseRule::False,
+        },
+        &[],
+        0,
+        1,
+        8,
+        1,
+        1,
+    )
+    .ok_or("Hierarchical collapse failed")?;
+
+    if fine.get_width() != 12 || fine.get_height() != 8 {
+        return Err(format!("Unexpected size {}x{}", fine.get_width(), fine.get_height()));
+    }
+    for i in 0..fine.get_area() {
+        let pos = fine.i_to_pos(i);
+        let parent = coarse.get(&Pos::new(pos.x / 4, pos.y / 4), 0);
+        let tile = fine.get(&pos, 0);
+        let allowed = match parent {
+            2 => [4, 5, 7].contains(&tile),
+            _ => [6, 7].contains(&tile),
+        };
+        if !allowed {
+            return Err(format!("{tile} at {pos:?} inside {parent}"));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_collapse_hierarchical_seams() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{evaluate_rule, CollapseRule, Truth};
+    use crate::ffc::hierarchy::collapse_hierarchical;
+
+    // Land (2) and sea (3) biomes. Forest (5) only grows along the edge of the world, and grass (4) and water (6) never
+    // touch, including across the seam between two blocks. The blocks are solved without a margin too, where nothing
+    // but checking the seam afterwards keeps them apart, so sand is weighted up to keep the blocks from running out of
+    // attempts.
+    let coarse = Grid::new(
+        vec![
+            2, 3, //
+            2, 3, //
+        ],
+        2,
+    );
+    let fine_rule = |parent: &u8, tile: &u8| match (*parent, *tile) {
+        (_, 7) => CollapseRule::True,
+        (2, 4) => CollapseRule::Not(Box::new(CollapseRule::NextTo1(Box::new(CollapseRule::Is(6))))),
+        (2, 5) => CollapseRule::NextTo1(Box::new(CollapseRule::Outer)),
+        (3, 6) => CollapseRule::Not(Box::new(CollapseRule::NextTo1(Box::new(CollapseRule::Is(4))))),
+        _ => CollapseRule::False,
+    };
+
+    for margin in [0, 1, 2] {
+        for _ in 0..10 {
+            let fine = collapse_hierarchical(
+                &coarse,
+                2,
+                &[4u8, 5, 6, 7],
+                fine_rule,
+                &[1.0, 1.0, 1.0, 3.0],
+                0,
+                1,
+                8,
+                1,
+                margin,
+            )
+            .ok_or(format!("Hierarchical collapse failed with a margin of {margin}"))?;
+            for i in 0..fine.get_area() {
+                let pos = fine.i_to_pos(i);
+                let parent = coarse.get(&Pos::new(pos.x / 2, pos.y / 2), 0);
+                let tile = fine.get(&pos, 0);
+                if evaluate_rule(&fine, &fine, &pos, &fine_rule(&parent, &tile), 0, 1, 8) != Truth::True {
+                    return Err(format!("{tile} at {pos:?} breaks its rule with a margin of {margin}"));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_collapse_sequence() -> Result<(), String> {
+    use crate::ffc::collapse_rules::CollapseRule;
+    use crate::ffc::sequence::collapse_sequence;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Grass (2) and dirt (3) swap every time a cell is solved again
+    let rules = [CollapseRule::Was(3u8), CollapseRule::Was(2)];
+    let first = Grid::new(vec![2; 16], 4);
+    let changed = |a: &Grid<u8>, b: &Grid<u8>| a.get_cells().iter().zip(b.get_cells()).filter(|(a, b)| a != b).count();
+
+    let limited = collapse_sequence(
+        first.clone(),
+        3,
+        &[2, 3],
+        |tile| &rules[(*tile - 2) as usize],
+        &[],
+        0,
+        1,
+        8,
+        1,
+        &[],
+        Some(3),
+        &mut StdRng::seed_from_u64(5),
+    )
+    .ok_or("Limited sequence failed")?;
+    if limited.len() != 4 || limited.windows(2).any(|frames| changed(&frames[0], &frames[1]) != 3) {
+        return Err(String::from("Expected exactly 3 cells to change each frame"));
+    }
+
+    // The same seed picks the same cells to change
+    let repeated = collapse_sequence(
+        first.clone(),
+        3,
+        &[2, 3],
+        |tile| &rules[(*tile - 2) as usize],
+        &[],
+        0,
+        1,
+        8,
+        1,
+        &[],
+        Some(3),
+        &mut StdRng::seed_from_u64(5),
+    )
+    .ok_or("Repeated sequence failed")?;
+    if repeated
+        .iter()
+        .zip(&limited)
+        .any(|(a, b)| a.get_cells() != b.get_cells())
+    {
+        return Err(String::from("Expected the same seed to give the same sequence"));
+    }
+
+    let unlimited = collapse_sequence(
+        first,
+        2,
+        &[2, 3],
+        |tile| &rules[(*tile - 2) as usize],
+        &[],
+        0,
+        1,
+        8,
+        1,
+        &[],
+        None,
+        &mut StdRng::seed_from_u64(5),
+    )
+    .ok_or("Unlimited sequence failed")?;
+    if unlimited[1].get_cells().iter().any(|tile| *tile != 3) || unlimited[2].get_cells().iter().any(|tile| *tile != 2)
+    {
+        return Err(String::from("Expected every cell to change each frame"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_backjumping() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{collapse_rule_with_rng, CollapseRule, BACKTRACK_ATTEMPTS};
+    use crate::ffc::learn::learn_rules;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    // Rows that always run water (2), sand (3), then grass (4)
+    let sample = Grid::new(
+        vec![
+            2, 2, 2, 2, //
+            3, 3, 3, 3, //
+            4, 4, 4, 4, //
+            4, 4, 4, 4, //
+        ],
+        4,
+    );
+    let learned = learn_rules(&sample, 0, None);
+    let generate = |rules: &HashMap<u8, CollapseRule<u8>>, seed| {
+        collapse_rule_with_rng(
+            initialize(24, 24, 0u8),
+            &initialize(24, 24, 0u8),
+            &learned.tiles,
+            |tile| &rules[tile],
+            &[],
+            0,
+            1,
+            16,
+            1,
+            &[],
+            BACKTRACK_ATTEMPTS,
+            None,
+            &mut StdRng::seed_from_u64(seed),
+            &mut |_, _| {},
+        )
+    };
+
+    for seed in 0..10 {
+        let generated = generate(&learned.rules, seed).ok_or(format!("Failed to generate a grid with seed {seed}"))?;
+
+        // Every pair of cells one row apart must have been seen in the sample
+        for i in 0..generated.get_area() {
+            let pos = generated.i_to_pos(i);
+            let (tile, next) = (generated.get(&pos, 1), generated.get(&pos.rel(0, 1), 1));
+            if next != 1 && !matches!((tile, next), (2, 3) | (3, 4) | (4, 4)) {
+                return Err(format!("Unexpected {next} after {tile} at ({}, {})", pos.x, pos.y));
+            }
+        }
+    }
+
+    // The same rules behind a Custom predicate that always passes aren't monotone, so the solver can only undo
+    // placements in order, which gets stuck on some of the seeds that backjumping solved above
+    let chronological = learn_rules(&sample, 0, None)
+        .rules
+        .into_iter()
+        .map(|(tile, rule)| {
+            let always: CollapseRule<u8> = CollapseRule::Custom(Arc::new(|_, _, _| true), 0);
+            (tile, CollapseRule::And(vec![always, rule]))
+        })
+        .collect::<HashMap<_, _>>();
+    let stuck = (0..10)
+        .filter(|&seed| generate(&chronological, seed).is_none())
+        .collect::<Vec<_>>();
+    if stuck != [0, 2, 3, 5, 9] {
+        return Err(format!(
+            "Expected undoing placements in order to get stuck on the same seeds, got {stuck:?}"
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_restart_policies() -> Result<(), String> {
+    use crate::ffc::collapse_rules::{collapse_rule_with_rng, CollapseRule, BACKTRACK_ATTEMPTS};
+    use crate::ffc::learn::learn_rules;
+    use crate::ffc::restart::{collapse_rule_restarting, luby, RestartPolicy};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    let sequence = (1..=15).map(luby).collect::<Vec<_>>();
+    if sequence != vec![1, 1, 2, 1, 1, 2, 4, 1, 1, 2, 1, 1, 2, 4, 8] {
+        return Err(format!("Unexpected Luby sequence {sequence:?}"));
+    }
+    let geometric = RestartPolicy::Geometric {
+        initial: 2,
+        factor: 1.5,
+    };
+    if (0..4).map(|run| geometric.attempts(run)).collect::<Vec<_>>() != vec![2, 3, 5, 7] {
+        return Err(String::from("Unexpected geometric attempts"));
+    }
+
+    // The same base seed has to give the same output every time
+    let rules = [
+        CollapseRule::True,
+        CollapseRule::Not(Box::new(CollapseRule::NextTo(Box::new(CollapseRule::Is(3u8))))),
+    ];
+    let generate = |base_seed| {
+        collapse_rule_restarting(
+            initialize(8, 8, 0u8),
+            &initialize(8, 8, 0u8),
+            &[2, 3],
+            |tile| &rules[(*tile - 2) as usize],
+            &[],
+            0,
+            1,
+            8,
+            1,
+            &[],
+            RestartPolicy::Luby { unit: 4 },
+            10,
+            base_seed,
+        )
+    };
+    let (first, first_stats) = generate(7);
+    let (second, second_stats) = generate(7);
+    let first = first.ok_or("Failed to generate a grid")?;
+    if Some(first.get_cells()) != second.as_ref().map(|grid| grid.get_cells()) || first_stats != second_stats {
+        return Err(String::from("The same seed gave different results"));
+    }
+    if first_stats.attempts.first() != Some(&4) || first_stats.runs != first_stats.seeds.len() {
+        return Err(format!("Unexpected stats {first_stats:?}"));
+    }
+
+    // Rows that always run water (2), sand (3), then grass (4), which runs into dead ends often. With only a couple
+    // allowed per run, runs that could have finished are abandoned and the next one started.
+    let sample = Grid::new(
+        vec![
+            2, 2, 2, 2, //
+            3, 3, 3, 3, //
+            4, 4, 4, 4, //
+            4, 4, 4, 4, //
+        ],
+        4,
+    );
+    let learned = learn_rules(&sample, 0, None);
+    let solve = |rng: &mut StdRng, max_backtracks| {
+        collapse_rule_with_rng(
+            initialize(16, 16, 0u8),
+            &initialize(16, 16, 0u8),
+            &learned.tiles,
+            |tile| &learned.rules[tile],
+            &[],
+            0,
+            1,
+            16,
+            1,
+            &[],
+            BACKTRACK_ATTEMPTS,
+            max_backtracks,
+            rng,
+            &mut |_, _| {},
+        )
+    };
+    let (restarted, stats) = collapse_rule_restarting(
+        initialize(16, 16, 0u8),
+        &initialize(16, 16, 0u8),
+        &learned.tiles,
+        |tile| &learned.rules[tile],
+        &[],
+        0,
+        1,
+        16,
+        1,
+        &[],
+        RestartPolicy::Fixed { attempts: 2 },
+        50,
+        1,
+    );
+    restarted.ok_or("Failed to generate a grid with restarts")?;
+    if stats.runs < 2 {
+        return Err(String::from("Expected the first run to be abandoned"));
+    }
+    // The first run only failed for running out of attempts, left alone it would have finished
+    let first_seed = stats.seeds[0];
+    if solve(&mut StdRng::seed_from_u64(first_seed), Some(2)).is_some()
+        || solve(&mut StdRng::seed_from_u64(first_seed), None).is_none()
+    {
+        return Err(format!("Expected the first run to be cut short, from {stats:?}"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_repair_rule() -> Result<(), String> {
+    use crate::ffc::collapse_rules::CollapseRule;
+    use crate::ffc::repair::{fill_random, repair_rule};
+    use crate::ffc::validate::validate_rules;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Flowers (3) can't be next to one another, grass (2) goes anywhere
+    let rules = [
+        CollapseRule::True,
+        CollapseRule::Not(Box::new(CollapseRule::NextTo1(Box::new(CollapseRule::Is(3u8))))),
+    ];
+    let mut start = fill_random(initialize(16, 16, 0u8), &[2, 3], 0, &mut StdRng::seed_from_u64(3));
+    start.set(&Pos::new(0, 0), 3);
+    start.set(&Pos::new(1, 0), 3);
+    let history = initialize(16, 16, 0u8);
+
+    let (_, untouched) = repair_rule(
+        start.clone(),
+        &history,
+        &[2, 3],
+        |tile| &rules[(*tile - 2) as usize],
+        0,
+        1,
+        8,
+        0,
+        0.0,
+        None,
+        &mut StdRng::seed_from_u64(3),
+    );
+    if untouched.is_valid() || untouched.iterations != 0 {
+        return Err(String::from("Expected the starting grid to have violations"));
+    }
+
+    let repair = |pinned: Option<&[bool]>| {
+        repair_rule(
+            start.clone(),
+            &history,
+            &[2, 3],
+            |tile| &rules[(*tile - 2) as usize],
+            0,
+            1,
+            8,
+            10_000,
+            0.05,
+            pinned,
+            &mut StdRng::seed_from_u64(3),
+        )
+    };
+    let (repaired, report) = repair(None);
+    if !report.is_valid() {
+        return Err(format!(
+            "{} violations left after {} iterations",
+            report.violations.len(),
+            report.iterations
+        ));
+    }
+    for i in 0..repaired.get_area() {
+        let pos = repaired.i_to_pos(i);
+        if repaired.get(&pos, 1) == 3 && (repaired.get(&pos.rel(1, 0), 1) == 3 || repaired.get(&pos.rel(0, 1), 1) == 3)
+        {
+            return Err(format!("Flowers next to one another at ({}, {})", pos.x, pos.y));
+        }
+    }
+    if repair(None).0.get_cells() != repaired.get_cells() {
+        return Err(String::from("The same seed gave a different repair"));
+    }
+    // Whatever repair calls valid has to pass the validator too
+    let validated = validate_rules(
+        &repaired,
+        &history,
+        &[2, 3],
+        |tile| &rules[(*tile - 2) as usize],
+        0,
+        1,
+        8,
+    );
+    if !validated.is_empty() {
+        return Err(format!("Repaired grid fails validation at {} cells", validated.len()));
+    }
+
+    // A pinned flower stays put, and the flower beside it is moved instead
+    let mut pinned = vec![false; start.get_area()];
+    pinned[start.pos_to_i(&Pos::new(0, 0))] = true;
+    let (repaired, report) = repair(Some(&pinned));
+    if !report.is_valid() || repaired.get(&Pos::new(0, 0), 1) != 3 || repaired.get(&Pos::new(1, 0), 1) == 3 {
+        return Err(String::from(
+            "Expected the pinned flower to be kept and its neighbour moved",
+        ));
+    }
+
+    // Pinning both flowers leaves nothing that can fix them, so they are reported
+    pinned[start.pos_to_i(&Pos::new(1, 0))] = true;
+    let (repaired, report) = repair(Some(&pinned));
+    if repaired.get(&Pos::new(1, 0), 1) != 3 || !report.violations.iter().any(|pos| pos.x == 0 && pos.y == 0) {
+        return Err(format!(
+            "Expected the pinned flowers to be reported, got {:?}",
+            report.violations
+        ));
+    }
+
+    // A rule that only ever reads the cell itself, which is never false while the cell is unset but always false once
+    // it holds grass, so every cell is in violation
+    let self_rule = CollapseRule::Right(Box::new(CollapseRule::Left(Box::new(CollapseRule::Not(Box::new(
+        CollapseRule::Is(2u8),
+    ))))));
+    let grass = Grid::new(vec![2u8; 9], 3);
+    let (grass, report) = repair_rule(
+        grass,
+        &initialize(3, 3, 0u8),
+        &[2],
+        |_| &self_rule,
+        0,
+        1,
+        8,
+        100,
+        0.0,
+        None,
+        &mut StdRng::seed_from_u64(3),
+    );
+    let validated = validate_rules(&grass, &initialize(3, 3, 0u8), &[2], |_| &self_rule, 0, 1, 8);
+    if report.violations.len() != 9 || validated.len() != 9 {
+        return Err(format!(
+            "Expected repair and the validator to both find 9 violations, got {} and {}",
+            report.violations.len(),
+            validated.len()
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_validators() -> Result<(), String> {
+    use crate::ffc::collapse_rules::CollapseRule;
+    use crate::ffc::validate::{validate_pattern, validate_rules, PatternViolation, RuleViolation};
+
+    // Flowers (3) can't be next to one another, grass (2) goes anywhere
+    let rules = [
+        CollapseRule::True,
+        CollapseRule::Not(Box::new(CollapseRule::NextTo1(Box::new(CollapseRule::Is(3u8))))),
+    ];
+    let grid = Grid::new(
+        vec![
+            3, 3, 2, //
+            2, 2, 0, //
+            2, 9, 3, //
+        ],
+        3,
+    );
+    let violations = validate_rules(
+        &grid,
+        &initialize(3, 3, 0u8),
+        &[2, 3],
+        |tile| &rules[(*tile - 2) as usize],
+        0,
+        1,
+        8,
+    );
+
+    let summary = violations
+        .iter()
+        .map(|violation| match violation {
+            RuleViolation::Unset { pos } => format!("unset ({}, {})", pos.x, pos.y),
+            RuleViolation::UnknownTile { pos, tile } => format!("unknown {tile} ({}, {})", pos.x, pos.y),
+            RuleViolation::FailedRule { pos, tile, trace } => {
+                format!("{tile} {:?} ({}, {})", trace.result, pos.x, pos.y)
+            }
+        })
+        .collect::<Vec<_>>();
+    if summary
+        != vec![
+            "3 False (0, 0)",
+            "3 False (1, 0)",
+            "unset (2, 1)",
+            "unknown 9 (1, 2)",
+            "3 Unknown (2, 2)",
+        ]
+    {
+        return Err(format!("Unexpected violations {summary:?}"));
+    }
+
+    // A checkerboard sample can't contain two of the same tile side by side. With outer the same as unset, anything
+    // past the edges matches, so only the window entirely inside the output fails.
+    let sample = Grid::new(
+        vec![
+            2, 3, 2, 3, //
+            3, 2, 3, 2, //
+            2, 3, 2, 3, //
+            3, 2, 3, 2, //
+        ],
+        4,
+    );
+    let output = Grid::new(
+        vec![
+            3, 2, 3, //
+            2, 2, 2, //
+            3, 2, 3, //
+        ],
+        3,
+    );
+    let summarize = |violations: Vec<PatternViolation<u8>>| {
+        violations
+            .iter()
+            .map(|violation| match violation {
+                PatternViolation::Unset { pos } => (pos.x, pos.y, vec![]),
+                PatternViolation::Unmatched { pos, window } => (pos.x, pos.y, window.get_cells().clone()),
+            })
+            .collect::<Vec<_>>()
+    };
+    let violations = summarize(validate_pattern(&output, &sample, 1, 0, 0));
+    if violations != vec![(1, 1, vec![3, 2, 3, 2, 2, 2, 3, 2, 3])] {
+        return Err(format!("Unexpected pattern violations {violations:?}"));
+    }
+
+    // An unset cell would match anything, so it has to be reported on its own
+    let mut unfinished = sample.clone();
+    unfinished.set(&Pos::new(1, 2), 0);
+    let violations = summarize(validate_pattern(&unfinished, &sample, 1, 0, 1));
+    if violations != vec![(1, 2, vec![])] {
+        return Err(format!(
+            "Unexpected pattern violations for an unset cell {violations:?}"
+        ));
+    }
+    if !validate_pattern(&sample, &sample, 1, 0, 0).is_empty() {
+        return Err(String::from("The sample should always be valid against itself"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_batch_generation() -> Result<(), String> {
+    use crate::ffc::batch::{collapse_rule_batch, similarity, Dedup};
+    use crate::ffc::collapse_rules::CollapseRule;
+
+    let rules = [CollapseRule::True, CollapseRule::True];
+    let batch = |width, count, max_attempts, threads, dedup| {
+        collapse_rule_batch(
+            &initialize(width, 2, 0u8),
+            &initialize(width, 2, 0u8),
+            &[2, 3],
+            |tile| &rules[(*tile - 2) as usize],
+            &[],
+            0,
+            1,
+            8,
+            1,
+            &[],
+            count,
+            max_attempts,
+            42,
+            threads,
+            dedup,
+        )
+    };
+
+    // The same base seed gives the same batch however many threads share the work, including when a round makes more
+    // attempts than are needed
+    for (count, threads) in [(8, 4), (3, 4)] {
+        let (parallel, parallel_stats) = batch(6, count, 8, threads, Dedup::Identical);
+        let (serial, serial_stats) = batch(6, count, 8, 1, Dedup::Identical);
+        if parallel.len() != count
+            || parallel_stats != serial_stats
+            || parallel
+                .iter()
+                .zip(serial.iter())
+                .any(|(a, b)| a.seed != b.seed || a.grid.get_cells() != b.grid.get_cells())
+        {
+            return Err(format!("Parallel and serial batches of {count} differ"));
+        }
+    }
+
+    // A 1x2 grid of two tiles only has 4 distinct outputs
+    let (distinct, stats) = batch(1, 6, 100, 4, Dedup::Identical);
+    if distinct.len() != 4 || stats.duplicates == 0 || stats.attempts != 100 {
+        return Err(format!(
+            "Expected 4 distinct outputs, found {} with {stats:?}",
+            distinct.len()
+        ));
+    }
+    for (i, a) in distinct.iter().enumerate() {
+        if distinct[i + 1..].iter().any(|b| similarity(&a.grid, &b.grid) == 1.0) {
+            return Err(String::from("Kept a duplicate output"));
+        }
+    }
+
+    // Outputs sharing either of their two cells are too similar, which leaves room for 2 at most
+    let (dissimilar, stats) = batch(1, 4, 100, 4, Dedup::Similarity(0.5));
+    if dissimilar.len() != 2 || stats.duplicates == 0 {
+        return Err(format!(
+            "Expected 2 dissimilar outputs, found {} with {stats:?}",
+            dissimilar.len()
+        ));
+    }
+    if similarity(&dissimilar[0].grid, &dissimilar[1].grid) != 0.0 {
+        return Err(String::from("Kept outputs that share a cell"));
+    }
+    Ok(())
+}